@@ -1,102 +1,867 @@
 use std::{
-    collections::HashMap,
-    fs::File,
-    io::{self, Read, Write},
-    net::{TcpListener, TcpStream},
-    path::PathBuf,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    fs::Metadata,
+    hash::Hasher,
+    io::{self, BufReader, SeekFrom, Write},
+    net::{IpAddr, SocketAddr},
+    panic::AssertUnwindSafe,
+    path::{Component, Path, PathBuf},
     pin::Pin,
-    thread,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use flate2::{
+    read::{GzDecoder, ZlibDecoder},
+    write::GzEncoder,
+    Compression, Crc,
+};
+use futures::FutureExt;
 use lazy_static::lazy_static;
-use nom::AsBytes;
 use regex::Regex;
+use socket2::{Domain, Socket, Type};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::{
+        AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt,
+        BufReader as AsyncBufReader, ReadBuf,
+    },
+    net::{TcpListener, TcpStream},
+    sync::{Notify, OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
+    time,
+};
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
 
-const GET: &'static str = "GET";
-const POST: &'static str = "POST";
+const PORT_ARG: &str = "--port";
+const DEFAULT_PORT: u16 = 4221;
+const BIND_ARG: &str = "--bind";
+// Listen on a Unix domain socket at this path instead of TCP
+const BIND_UNIX_ARG: &str = "--bind-unix";
+const DEFAULT_BIND: &str = "127.0.0.1";
+const ECHO_PATH: &str = "echo/";
+const USER_AGENT_PATH: &str = "user-agent";
+const HEALTH_PATHS: [&str; 2] = ["health", "healthz"];
+const METRICS_PATH: &str = "metrics";
+const FILES_PATH: &str = "files";
+const UPLOAD_PATH: &str = "upload";
+const DIR_PATH: &str = "--directory";
+const VHOST_ARG: &str = "--vhost";
+const REDIRECT_ARG: &str = "--redirect";
+// Expand redirect targets like `/new` into `http://host/new` for older clients
+const ABSOLUTE_REDIRECTS_ARG: &str = "--absolute-redirects";
+const CORS_ORIGIN_ARG: &str = "--cors-origin";
+const ASTERISK_TARGET: &str = "*";
+// Every method some route answers
+const SERVER_METHODS: &str = "GET, HEAD, POST, PUT, PATCH, OPTIONS, TRACE";
+const CORS_DEFAULT_ALLOW_HEADERS: &str = "Content-Type";
+const AUTH_ARG: &str = "--auth";
+const AUTH_REALM: &str = "files";
+const FORM_DATA: &str = "multipart/form-data";
+const OCTET_STREAM: &str = "application/octet-stream";
+const GZIP: &str = "gzip";
+const CACHE_BYTES_ARG: &str = "--cache-bytes";
+// Larger files are always read from disk so one of them can't flush the whole cache
+const CACHE_MAX_FILE_BYTES: usize = 1024 * 1024;
+const GZIP_SUFFIX: &str = ".gz";
+// Bodies below this many bytes are sent as they are, compressing would barely shrink them
+const GZIP_MIN_SIZE_ARG: &str = "--gzip-min-size";
+const DEFAULT_GZIP_MIN_SIZE: usize = 1024;
+const BROTLI: &str = "br";
+// The absence of any coding, acceptable unless refused outright
+const IDENTITY: &str = "identity";
+// Brotli quality and window size, a middle ground between speed and size
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW_BITS: u32 = 22;
+// Precompressed siblings of a file, tried in order of preference
+const PRECOMPRESSED: [(&str, &str); 2] = [(BROTLI, ".br"), (GZIP, GZIP_SUFFIX)];
+const APPEND_QUERY: &str = "append";
+const DOWNLOAD_QUERY: &str = "download";
+const CHUNKED: &str = "chunked";
+const RESPONSE_CHUNK_BYTES: usize = 8 * 1024;
+// Range sets with more parts than this, once overlaps are merged, get the whole file
+const MAX_RANGES: usize = 16;
+// Send whole files chunked with a CRC-32 of the body in a trailer, to clients that
+// allow trailers with `TE: trailers`
+const CHECKSUM_TRAILER_ARG: &str = "--checksum-trailer";
+const CHECKSUM_TRAILER: &str = "X-Checksum";
+const LIST_DIRS_ARG: &str = "--list-directories";
+// Hides the routes that only exist for testing, see `is_debug_route`
+const NO_DEBUG_ROUTES_ARG: &str = "--no-debug-routes";
+const INDEX_FILE_ARG: &str = "--index-file";
+// Served for `/` and by the SPA fallback when there is no `--index-file`
+const INDEX_PAGE: &str = "index.html";
+// Unmatched GETs outside the API prefix are answered with the SPA's index page
+const SPA_FALLBACK_ARG: &str = "--spa-fallback";
+const SPA_API_PREFIX_ARG: &str = "--spa-api-prefix";
+const DEFAULT_SPA_API_PREFIX: &str = "api";
+const NO_FOLLOW_SYMLINKS_ARG: &str = "--no-follow-symlinks";
+// Tag files by size and modification time instead of hashing their contents. The
+// tags are weak, so they never satisfy `If-Match` or `If-Range`.
+const WEAK_ETAGS_ARG: &str = "--weak-etags";
+// Repeatable globs on file names, `--deny` hides matches and a non-empty `--allow`
+// hides everything else
+const DENY_ARG: &str = "--deny";
+const ALLOW_ARG: &str = "--allow";
+// Repeatable `--cache-control "*.png=public, max-age=31536000"`, the first glob
+// matching a served file name decides its Cache-Control
+const CACHE_CONTROL_ARG: &str = "--cache-control";
+const TEMPLATING_ARG: &str = "--templating";
+const TEMPLATE_VAR_ARG: &str = "--template-var";
+const NOT_FOUND_PAGE: &str = "404.html";
+const EXPECT_CONTINUE: &str = "100-continue";
+const RATE_LIMIT_ARG: &str = "--rate-limit";
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+const RATE_LIMIT_PRUNE_AT: usize = 1024;
+const SINGLETON_HEADERS: [&str; 2] = ["Content-Length", "Host"];
+const LOG_LEVEL_ARG: &str = "--log-level";
+// Only errors are printed, the access log stays off unless asked for with `--access-log`
+const QUIET_ARG: &str = "--quiet";
+const ACCESS_LOG_ARG: &str = "--access-log";
+const READ_BUFFER_ARG: &str = "--read-buffer";
+const DEFAULT_READ_BUFFER_BYTES: usize = 8 * 1024;
+const READ_BUFFER_RANGE: std::ops::RangeInclusive<usize> = 64..=1024 * 1024;
+const MAX_HEADER_BYTES: usize = 8 * 1024;
+// Largest request body, decoded, unlimited unless given
+const MAX_BODY_ARG: &str = "--max-body-bytes";
+// Chunk size lines, extensions included, and trailer lines must fit in this
+const MAX_CHUNK_LINE_BYTES: usize = 4 * 1024;
+// Repeatable `--header "Name: Value"` added to every response
+const HEADER_ARG: &str = "--header";
+const MAX_URI_ARG: &str = "--max-uri-bytes";
+const DEFAULT_MAX_URI_BYTES: usize = 8 * 1024;
+const TLS_CERT_ARG: &str = "--tls-cert";
+const TLS_KEY_ARG: &str = "--tls-key";
+const KEEPALIVE_TIMEOUT_ARG: &str = "--keepalive-timeout";
+const DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+// Limit on receiving a whole request, counted from its first byte
+const REQUEST_TIMEOUT_ARG: &str = "--request-timeout";
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const KEEPALIVE_MAX_ARG: &str = "--keepalive-max";
+const DEFAULT_KEEPALIVE_MAX: usize = 100;
+// Loopback port taking `SHUTDOWN` and `STATS` commands, one per line
+const ADMIN_PORT_ARG: &str = "--admin-port";
+// Log the client named by X-Forwarded-For or Forwarded instead of the socket peer
+const TRUST_PROXY_ARG: &str = "--trust-proxy";
+// Connections served at once, further ones wait in a queue of bounded length and
+// are turned away with a 503 once it is full
+const WORKERS_ARG: &str = "--workers";
+const QUEUE_CAPACITY_ARG: &str = "--queue-capacity";
+const DEFAULT_QUEUE_CAPACITY: usize = 64;
+// Open connections past which new ones are refused outright
+const MAX_CONNECTIONS_ARG: &str = "--max-connections";
+const OVERLOAD_RETRY_AFTER_SECS: u64 = 1;
+// File of further arguments, one `--flag value` per line, read again on SIGHUP.
+// Arguments on the command line take precedence over it.
+const CONFIG_FILE_ARG: &str = "--config";
+// Let other processes bind the same address, e.g. a replacement server started
+// before this one exits
+const REUSE_PORT_ARG: &str = "--reuse-port";
+// Validate the arguments and the files they name, then exit without listening
+const CHECK_ARG: &str = "--check";
+// Connections the kernel queues before they are accepted
+const BACKLOG_ARG: &str = "--backlog";
+const DEFAULT_BACKLOG: u32 = 1024;
+// How often idle keep-alive connections are checked against the timeout
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+const READ_POLL_INTERVAL: Duration = Duration::from_millis(500);
+// How long and how much a closing connection keeps reading to swallow pipelined data
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(500);
+const DRAIN_MAX_BYTES: usize = 64 * 1024;
 
-// const PUT: &'static str = "PUT";
-// const UPDATE: &'static str = "UPDATE";
-// const DELETE: &'static str = "DELETE";
-// const HEAD: &'static str = "HEAD";
-// const CONNECT: &'static str = "CONNECT";
-// const OPTIONS: &'static str = "OPTIONS";
-// const TRACE: &'static str = "TRACE";
-// const PATCH: &'static str = "PATCH";
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
 
-const USER_AGENT_PATH: &'static str = "user-agent";
-const FILES_PATH: &'static str = "files";
-const DIR_PATH: &'static str = "--directory";
+// Connections open across every server in the process, reported by /metrics
+static ACTIVE_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+static NEXT_UPLOAD_ID: AtomicU64 = AtomicU64::new(0);
+// Counters reported by /metrics
+static REQUESTS_TOTAL: AtomicU64 = AtomicU64::new(0);
+// Responses by status class, 1xx through 5xx
+static RESPONSES_BY_CLASS: [AtomicU64; 5] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+static BYTES_SERVED: AtomicU64 = AtomicU64::new(0);
+// Upper bounds of the request duration buckets, the last bucket takes the rest
+const LATENCY_BOUNDS: [Duration; 4] = [
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+];
+static LATENCY_BUCKETS: [AtomicU64; 5] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+static LATENCY_SUM_MICROS: AtomicU64 = AtomicU64::new(0);
 
 lazy_static! {
+    static ref STARTED_AT: Instant = Instant::now();
+    // Invalid arguments fall back to the defaults here, `main` refuses to start on
+    // them. Replaced as a whole on SIGHUP, connections keep the one they started with.
+    static ref CONFIG: RwLock<Arc<Config>> =
+        RwLock::new(Arc::new(Config::from_args().unwrap_or_default()));
+    static ref FILE_CACHE: Mutex<FileCache> = Mutex::new(FileCache::new());
+    // Open connections by id, scanned by `reap_idle_connections`
+    static ref CONNECTIONS: Mutex<HashMap<u64, TrackedConnection>> = Mutex::new(HashMap::new());
+    // Requests seen per client address in the current window, and when it began
+    static ref RATE_LIMITS: Mutex<HashMap<IpAddr, (usize, Instant)>> = Mutex::new(HashMap::new());
     static ref USER_AGENT_RE: Regex = Regex::new(r"User-Agent:\s*([^\r\n]*)").unwrap();
-    static ref ECHO_RE: Regex = Regex::new(r"echo/([^\s\r\n]*)").unwrap();
-    static ref FILE_NAME_RE: Regex = Regex::new(r"files/([^\s\r\n]*)").unwrap();
-    static ref METHOD_RE: Regex = Regex::new(r"^(.*)\s+/.*\s+HTTP/1\.1").unwrap();
-    static ref PATH_RE: Regex = Regex::new(r".*\s+/(.*)\s+HTTP/1\.1").unwrap();
+    static ref FILE_NAME_RE: Regex = Regex::new(r"files/(.*)").unwrap();
+    static ref ABSOLUTE_TARGET_RE: Regex =
+        Regex::new(r"^([A-Za-z][A-Za-z0-9+.-]*)://([^/?#]*)(.*)$").unwrap();
     static ref HEADERS_RE: Regex = Regex::new(r"(.*?):\s*(.*)\s*").unwrap();
 }
 
+// Messages are printed when their level is at or above `--log-level`
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    fn parse(level: &str) -> Option<LogLevel> {
+        match level.to_ascii_lowercase().as_str() {
+            "debug" => Some(LogLevel::Debug),
+            "info" => Some(LogLevel::Info),
+            "warn" => Some(LogLevel::Warn),
+            "error" => Some(LogLevel::Error),
+            _ => None,
+        }
+    }
+
+    fn enabled(self, config: &Config) -> bool {
+        self >= config.log_level
+    }
+}
+
+// Warnings and errors go to stderr, apart from the access log on stdout
+macro_rules! log_at {
+    ($level:expr, $($arg:tt)*) => {
+        if $level.enabled(&current_config()) {
+            if $level >= LogLevel::Warn {
+                eprintln!($($arg)*);
+            } else {
+                println!($($arg)*);
+            }
+        }
+    };
+}
+
+macro_rules! log_error {
+    ($($arg:tt)*) => { log_at!(LogLevel::Error, $($arg)*) };
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)*) => { log_at!(LogLevel::Warn, $($arg)*) };
+}
+
+macro_rules! log_info {
+    ($($arg:tt)*) => { log_at!(LogLevel::Info, $($arg)*) };
+}
+
+macro_rules! log_debug {
+    ($($arg:tt)*) => { log_at!(LogLevel::Debug, $($arg)*) };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+struct HttpVersion {
+    major: u8,
+    minor: u8,
+}
+
+impl HttpVersion {
+    const HTTP_1_1: HttpVersion = HttpVersion { major: 1, minor: 1 };
+
+    // HTTP/1.0 connections are not persistent unless the client asks for it
+    fn closes_by_default(&self) -> bool {
+        self.major == 1 && self.minor == 0
+    }
+
+    // A later HTTP/1.x client is answered with the highest minor version we implement
+    fn answered_with(&self) -> HttpVersion {
+        if *self > HttpVersion::HTTP_1_1 {
+            HttpVersion::HTTP_1_1
+        } else {
+            *self
+        }
+    }
+}
+
+impl std::fmt::Display for HttpVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HTTP/{}.{}", self.major, self.minor)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Method {
+    Get,
+    Post,
+    Put,
+    Delete,
+    Head,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+}
+
+impl Method {
+    fn parse(s: &str) -> Option<Method> {
+        match s {
+            "GET" => Some(Method::Get),
+            "POST" => Some(Method::Post),
+            "PUT" => Some(Method::Put),
+            "DELETE" => Some(Method::Delete),
+            "HEAD" => Some(Method::Head),
+            "CONNECT" => Some(Method::Connect),
+            "OPTIONS" => Some(Method::Options),
+            "TRACE" => Some(Method::Trace),
+            "PATCH" => Some(Method::Patch),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Head => "HEAD",
+            Method::Connect => "CONNECT",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
+        }
+    }
+}
+
+impl std::fmt::Display for Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 #[allow(dead_code)]
+#[derive(Debug)]
 enum HttpResponse {
+    Continue,
     Ok(Option<String>),
     OkStream(Option<Vec<u8>>),
-    NotFound,
+    PartialContent {
+        contents: Vec<u8>,
+        ranges: Vec<(usize, usize)>,
+    },
+    NotFound(Option<Vec<u8>>),
     BadRequest,
     Created,
+    NoContent,
+    // 301, 302, 307 or 308 towards `location`
+    Redirect {
+        status: u16,
+        location: String,
+    },
+    NotModified,
+    Unauthorized,
+    Forbidden,
+    RequestTimeout,
+    LengthRequired,
+    NotAcceptable,
+    PreconditionFailed,
+    PayloadTooLarge,
+    UriTooLong,
+    // Carries the current size of the file
+    RangeNotSatisfiable(usize),
+    TooManyRequests(u64),
+    HeaderFieldsTooLarge,
+    InternalServerError,
+    NotImplemented,
+    HttpVersionNotSupported,
+    // Tells the client to retry after this many seconds
+    ServiceUnavailable(u64),
+}
+
+// The response for a failed file operation, by its status. A 404 is left without a
+// page so that the SPA fallback and `404.html` still apply.
+impl From<ServerError> for HttpResponse {
+    fn from(e: ServerError) -> Self {
+        match e.status() {
+            404 => HttpResponse::NotFound(None),
+            403 => HttpResponse::Forbidden,
+            400 => HttpResponse::BadRequest,
+            _ => HttpResponse::InternalServerError,
+        }
+    }
 }
 
+// The single place a response turns into bytes on the wire. Handlers and middleware
+// only ever pass `HttpResponse` or `ResponseBuilder` values around.
 trait IntoResponse {
-    fn into_response(&self) -> Vec<u8>;
+    fn into_response(self, version: &HttpVersion, config: &Config) -> Vec<u8>;
 }
 
-impl IntoResponse for HttpResponse {
-    fn into_response(&self) -> Vec<u8> {
+impl HttpResponse {
+    fn status(&self) -> (u16, &'static str) {
         match self {
-            HttpResponse::Ok(body) => {
-                return match body {
-                    Some(body) => {
-                        let content_length = body.as_bytes().len();
-                        format!(
-                        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
-                        content_length,
-                        body
-                    ).as_bytes().to_vec()
-                    }
-                    None => format!("HTTP/1.1 200 OK\r\n\r\n").as_bytes().to_vec(),
+            HttpResponse::Continue => (100, "CONTINUE"),
+            HttpResponse::Ok(_) | HttpResponse::OkStream(Some(_)) => (200, "OK"),
+            HttpResponse::PartialContent { .. } => (206, "PARTIAL CONTENT"),
+            HttpResponse::Redirect { status: 301, .. } => (301, "MOVED PERMANENTLY"),
+            HttpResponse::Redirect { status: 302, .. } => (302, "FOUND"),
+            HttpResponse::Redirect { status: 307, .. } => (307, "TEMPORARY REDIRECT"),
+            HttpResponse::Redirect { .. } => (308, "PERMANENT REDIRECT"),
+            HttpResponse::BadRequest | HttpResponse::OkStream(None) => (400, "BAD REQUEST"),
+            HttpResponse::NotModified => (304, "NOT MODIFIED"),
+            HttpResponse::Unauthorized => (401, "UNAUTHORIZED"),
+            HttpResponse::Forbidden => (403, "FORBIDDEN"),
+            HttpResponse::NotFound(_) => (404, "NOT FOUND"),
+            HttpResponse::RequestTimeout => (408, "REQUEST TIMEOUT"),
+            HttpResponse::LengthRequired => (411, "LENGTH REQUIRED"),
+            HttpResponse::NotAcceptable => (406, "NOT ACCEPTABLE"),
+            HttpResponse::PreconditionFailed => (412, "PRECONDITION FAILED"),
+            HttpResponse::PayloadTooLarge => (413, "PAYLOAD TOO LARGE"),
+            HttpResponse::UriTooLong => (414, "URI TOO LONG"),
+            HttpResponse::RangeNotSatisfiable(_) => (416, "RANGE NOT SATISFIABLE"),
+            HttpResponse::Created => (201, "CREATED"),
+            HttpResponse::NoContent => (204, "NO CONTENT"),
+            HttpResponse::TooManyRequests(_) => (429, "TOO MANY REQUESTS"),
+            HttpResponse::HeaderFieldsTooLarge => (431, "REQUEST HEADER FIELDS TOO LARGE"),
+            HttpResponse::InternalServerError => (500, "INTERNAL SERVER ERROR"),
+            HttpResponse::NotImplemented => (501, "NOT IMPLEMENTED"),
+            HttpResponse::HttpVersionNotSupported => (505, "HTTP VERSION NOT SUPPORTED"),
+            HttpResponse::ServiceUnavailable(_) => (503, "SERVICE UNAVAILABLE"),
+        }
+    }
+}
+
+// Computes a trailer field from the body once it has been encoded
+type TrailerValue = fn(&[u8]) -> String;
+
+// Wraps a response with the headers handlers and middleware add on top of it
+struct ResponseBuilder {
+    response: HttpResponse,
+    content_type: Option<String>,
+    headers: Vec<(String, String)>,
+    chunked: bool,
+    // Applied to the body when it is sent, set once the client was found to accept it
+    encoder: Option<&'static Encoder>,
+    // Fields sent after the last chunk, their values computed from the body as sent
+    trailers: Vec<(String, TrailerValue)>,
+}
+
+impl ResponseBuilder {
+    fn new(response: HttpResponse) -> Self {
+        ResponseBuilder {
+            response,
+            content_type: None,
+            headers: Vec::new(),
+            chunked: false,
+            encoder: None,
+            trailers: Vec::new(),
+        }
+    }
+
+    fn content_type(mut self, content_type: &str) -> Self {
+        self.content_type = Some(content_type.to_string());
+        self
+    }
+
+    fn header<V: ToString>(mut self, name: &str, value: V) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    // Sends the body with `Transfer-Encoding: chunked` instead of a Content-Length
+    fn chunked(mut self) -> Self {
+        self.chunked = true;
+        self
+    }
+
+    // Encodes the body with the coding `req` prefers, if it accepts any of ENCODERS
+    fn compress(mut self, req: &HttpRequest) -> Self {
+        self.encoder = negotiate_encoder(req);
+        self
+    }
+
+    // Trailers need chunked framing, so this also makes the response chunked.
+    // HTTP/1.0 clients get neither.
+    fn trailer(mut self, name: &str, value: TrailerValue) -> Self {
+        self.trailers.push((name.to_string(), value));
+        self.chunked()
+    }
+
+    // No route sets cookies yet
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn cookie(self, cookie: Cookie) -> Self {
+        self.header("Set-Cookie", cookie)
+    }
+
+    fn status(&self) -> (u16, &'static str) {
+        self.response.status()
+    }
+}
+
+// A `Set-Cookie` entry, every cookie is serialized onto its own header line
+#[cfg_attr(not(test), allow(dead_code))]
+struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    max_age: Option<i64>,
+    http_only: bool,
+    secure: bool,
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+impl Cookie {
+    // None unless `name` is an RFC 6265 token, anything else such as `;` or a line
+    // break would change the header
+    fn new(name: &str, value: &str) -> Option<Self> {
+        let token = !name.is_empty()
+            && name
+                .bytes()
+                .all(|byte| byte.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&byte));
+        token.then(|| Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            max_age: None,
+            http_only: false,
+            secure: false,
+        })
+    }
+
+    // Control characters and `;` can't appear in the attribute and are dropped
+    fn path(mut self, path: &str) -> Self {
+        self.path = Some(
+            path.chars()
+                .filter(|c| !c.is_control() && *c != ';')
+                .collect(),
+        );
+        self
+    }
+
+    fn max_age(mut self, seconds: i64) -> Self {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    fn http_only(mut self) -> Self {
+        self.http_only = true;
+        self
+    }
+
+    fn secure(mut self) -> Self {
+        self.secure = true;
+        self
+    }
+}
+
+impl std::fmt::Display for Cookie {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}=", self.name)?;
+        // RFC 6265 cookie-octets, anything else (and `%` itself) is percent-encoded
+        for byte in self.value.bytes() {
+            match byte {
+                0x21 | 0x23..=0x24 | 0x26..=0x2B | 0x2D..=0x3A | 0x3C..=0x5B | 0x5D..=0x7E => {
+                    write!(f, "{}", byte as char)?
                 }
+                _ => write!(f, "%{:02X}", byte)?,
+            }
+        }
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age)?;
+        }
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        Ok(())
+    }
+}
+
+impl IntoResponse for ResponseBuilder {
+    fn into_response(self, version: &HttpVersion, config: &Config) -> Vec<u8> {
+        let mut headers = Vec::new();
+        let (code, reason) = self.status();
+        // Handlers may override the type of the body, e.g. for files
+        let file_type = self
+            .content_type
+            .clone()
+            .unwrap_or_else(|| OCTET_STREAM.to_string());
+        let (content_type, content) = match self.response {
+            HttpResponse::Ok(Some(body)) => {
+                let content_type = self
+                    .content_type
+                    .unwrap_or_else(|| "text/plain".to_string());
+                (Some(content_type), body.into_bytes())
+            }
+            HttpResponse::OkStream(Some(body)) => {
+                headers.push(("Accept-Ranges".to_string(), "bytes".to_string()));
+                (Some(file_type), body)
             }
-            HttpResponse::OkStream(body) => match body {
-                Some(body) => {
-                    let content_length = body.len();
-                    let response_headers = format!(
-                                "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n\r\n",
-                                content_length,
-                            );
-                    [response_headers.as_bytes().to_vec(), body.to_owned()].concat()
+            HttpResponse::PartialContent { contents, ranges } => {
+                headers.push(("Accept-Ranges".to_string(), "bytes".to_string()));
+                let total = contents.len();
+                if let [(start, end)] = ranges[..] {
+                    headers.push((
+                        "Content-Range".to_string(),
+                        format!("bytes {}-{}/{}", start, end, total),
+                    ));
+                    (Some(file_type), contents[start..=end].to_vec())
+                } else {
+                    let boundary = multipart_boundary();
+                    let mut content = Vec::new();
+                    for (start, end) in ranges {
+                        let part_headers = format!(
+                            "--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                            boundary,
+                            with_charset(file_type.clone()),
+                            start,
+                            end,
+                            total
+                        );
+                        content.extend_from_slice(part_headers.as_bytes());
+                        content.extend_from_slice(&contents[start..=end]);
+                        content.extend_from_slice(b"\r\n");
+                    }
+                    content.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+                    let multipart_type = format!("multipart/byteranges; boundary={}", boundary);
+                    (Some(multipart_type), content)
                 }
-                None => HttpResponse::BadRequest.into_response(),
-            },
-            HttpResponse::BadRequest => format!("HTTP/1.1 400 BAD REQUEST\r\n\r\n")
-                .as_bytes()
-                .to_vec(),
-            HttpResponse::NotFound => format!("HTTP/1.1 404 NOT FOUND\r\n\r\n")
-                .as_bytes()
-                .to_vec(),
-            HttpResponse::Created => format!("HTTP/1.1 201 CREATED\r\n\r\n").as_bytes().to_vec(),
+            }
+            HttpResponse::NotFound(Some(page)) => (Some("text/html".to_string()), page),
+            HttpResponse::Unauthorized => {
+                headers.push((
+                    "WWW-Authenticate".to_string(),
+                    format!("Basic realm=\"{}\"", AUTH_REALM),
+                ));
+                (None, Vec::new())
+            }
+            HttpResponse::Redirect { location, .. } => {
+                headers.push(("Location".to_string(), location));
+                (None, Vec::new())
+            }
+            HttpResponse::RangeNotSatisfiable(total) => {
+                headers.push(("Content-Range".to_string(), format!("bytes */{}", total)));
+                (None, Vec::new())
+            }
+            HttpResponse::TooManyRequests(retry_after)
+            | HttpResponse::ServiceUnavailable(retry_after) => {
+                headers.push(("Retry-After".to_string(), retry_after.to_string()));
+                (None, Vec::new())
+            }
+            HttpResponse::OkStream(None) => {
+                let mut response = ResponseBuilder::new(HttpResponse::BadRequest);
+                response.headers = self.headers;
+                return response.into_response(version, config);
+            }
+            _ => (None, Vec::new()),
+        };
+        let mut head = Vec::new();
+        if let Some(content_type) = content_type {
+            head.push(("Content-Type".to_string(), with_charset(content_type)));
+        }
+        let has_body = code >= 200 && code != 204 && code != 304;
+        let content = match self.encoder.filter(|_| has_body) {
+            Some(encoder) => {
+                head.push(("Content-Encoding".to_string(), encoder.name.to_string()));
+                (encoder.encode)(&content)
+            }
+            None => content,
+        };
+        // Caches must key the response on Accept-Encoding once it was negotiated
+        let encoded = head.iter().chain(&self.headers).any(|(name, value)| {
+            name.eq_ignore_ascii_case("Content-Encoding") && !value.eq_ignore_ascii_case("identity")
+        });
+        if encoded {
+            headers.push(("Vary".to_string(), "Accept-Encoding".to_string()));
+        }
+        // HTTP/1.0 clients don't understand chunked framing and get the length instead
+        let chunked = has_body && self.chunked && *version >= HttpVersion::HTTP_1_1;
+        let content = if chunked {
+            head.push(("Transfer-Encoding".to_string(), CHUNKED.to_string()));
+            if !self.trailers.is_empty() {
+                let names: Vec<&str> = self
+                    .trailers
+                    .iter()
+                    .map(|(name, _)| name.as_str())
+                    .collect();
+                head.push(("Trailer".to_string(), names.join(", ")));
+            }
+            chunk_body(&content, &self.trailers)
+        } else {
+            // Always sent so a kept-alive client can tell where even an empty body ends
+            if has_body {
+                head.push(("Content-Length".to_string(), content.len().to_string()));
+            }
+            content
+        };
+        // Framing always follows the final encoded bytes, whatever a handler or
+        // `--header` tried to set
+        let extra = self
+            .headers
+            .into_iter()
+            .chain(config.default_headers.iter().cloned());
+        let extra = extra.filter(|(name, _)| {
+            !name.eq_ignore_ascii_case("Content-Length")
+                && !name.eq_ignore_ascii_case("Transfer-Encoding")
+        });
+        headers = head.into_iter().chain(headers).chain(extra).collect();
+
+        let mut response = format!("{} {} {}\r\n", version.answered_with(), code, reason);
+        for (name, value) in headers {
+            response.push_str(&format!("{}: {}\r\n", name, value));
         }
+        response.push_str("\r\n");
+        [response.into_bytes(), content].concat()
     }
 }
 
-fn extract_path_echo<T>(s: &T) -> Option<String>
-where
-    T: AsRef<str>,
-{
-    let string = s.as_ref();
-    let caps = ECHO_RE.captures(string)?;
-    let matching = caps.get(1)?;
-    Some(matching.as_str().to_string())
+// Undoes a gzip or deflate `Content-Encoding` on a request body, None when the body
+// was sent as is or is empty. Other codings are passed through untouched. At most one
+// byte past `max_body` is decompressed, enough for the caller to refuse the body.
+fn decode_body(
+    req: &HttpRequest<'_>,
+    body: &[u8],
+    max_body: Option<usize>,
+) -> io::Result<Option<Vec<u8>>> {
+    let Some(coding) = req
+        .header_str("Content-Encoding")
+        .filter(|_| !body.is_empty())
+    else {
+        return Ok(None);
+    };
+    let limit = max_body.map_or(u64::MAX, |max| max as u64 + 1);
+    let mut decoded = Vec::new();
+    match coding.trim().to_ascii_lowercase().as_str() {
+        "gzip" | "x-gzip" => io::Read::read_to_end(
+            &mut io::Read::take(GzDecoder::new(body), limit),
+            &mut decoded,
+        )?,
+        "deflate" => io::Read::read_to_end(
+            &mut io::Read::take(ZlibDecoder::new(body), limit),
+            &mut decoded,
+        )?,
+        _ => return Ok(None),
+    };
+    Ok(Some(decoded))
+}
+
+// Bodies are UTF-8, so textual types say so unless a charset was given already.
+// Binary types and JSON, which is UTF-8 by definition, are left as they are.
+fn with_charset(content_type: String) -> String {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    let textual = essence.starts_with("text/") || essence == "application/xml";
+    if textual && !content_type.to_ascii_lowercase().contains("charset=") {
+        format!("{}; charset=utf-8", content_type)
+    } else {
+        content_type
+    }
+}
+
+// A content coding the server can apply to response bodies
+struct Encoder {
+    name: &'static str,
+    encode: fn(&[u8]) -> Vec<u8>,
+}
+
+// Codings applied on the fly, the first one wins between equally preferred codings.
+// gzip leads, so it is what `*` gets. Brotli is only picked on the fly when the
+// client rates it higher, it costs more CPU than serving a `.br` sibling. Registering
+// another here makes it available to every handler that compresses.
+static ENCODERS: [Encoder; 2] = [
+    Encoder {
+        name: GZIP,
+        encode: gzip_body,
+    },
+    Encoder {
+        name: BROTLI,
+        encode: brotli_body,
+    },
+];
+
+fn brotli_body(content: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    let mut encoder =
+        brotli::CompressorWriter::new(&mut encoded, 4096, BROTLI_QUALITY, BROTLI_WINDOW_BITS);
+    // Writing into a Vec can't fail
+    encoder.write_all(content).unwrap();
+    drop(encoder);
+    encoded
+}
+
+fn gzip_body(content: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    // Writing into a Vec can't fail
+    encoder.write_all(content).unwrap();
+    encoder.finish().unwrap()
+}
+
+// Frames `content` as chunks of at most RESPONSE_CHUNK_BYTES followed by the last, empty
+// chunk and any trailers
+fn chunk_body(content: &[u8], trailers: &[(String, TrailerValue)]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(content.len() + 32);
+    for chunk in content.chunks(RESPONSE_CHUNK_BYTES) {
+        framed.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+        framed.extend_from_slice(chunk);
+        framed.extend_from_slice(b"\r\n");
+    }
+    framed.extend_from_slice(b"0\r\n");
+    for (name, value) in trailers {
+        framed.extend_from_slice(format!("{}: {}\r\n", name, value(content)).as_bytes());
+    }
+    framed.extend_from_slice(b"\r\n");
+    framed
+}
+
+// The value of the `X-Checksum` trailer
+fn crc32_checksum(body: &[u8]) -> String {
+    let mut crc = Crc::new();
+    crc.update(body);
+    format!("crc32={:08x}", crc.sum())
+}
+
+impl From<HttpResponse> for ResponseBuilder {
+    fn from(response: HttpResponse) -> Self {
+        ResponseBuilder::new(response)
+    }
+}
+
+impl IntoResponse for HttpResponse {
+    fn into_response(self, version: &HttpVersion, config: &Config) -> Vec<u8> {
+        ResponseBuilder::new(self).into_response(version, config)
+    }
 }
+
 fn extract_path_filename<T>(s: &T) -> Option<String>
 where
     T: AsRef<str>,
@@ -106,30 +871,62 @@ where
     let matching = caps.get(1)?;
     Some(matching.as_str().to_string())
 }
-fn extract_path<T>(s: &T) -> Option<String>
-where
-    T: AsRef<str>,
-{
-    let string = s.as_ref();
-    let caps = PATH_RE.captures(string)?;
-    let matching = caps.get(1)?;
-    Some(matching.as_str().to_string())
+
+// The version on the request line of a request that could not be parsed, so that the
+// error is still answered in the client's version. HTTP/1.1 when there is none.
+fn sent_version(buffer: &[u8]) -> HttpVersion {
+    let head = String::from_utf8_lossy(buffer);
+    head.split("\r\n")
+        .next()
+        .and_then(request_line_tokens)
+        .map_or(HttpVersion::HTTP_1_1, |(_, _, version)| version)
 }
 
-#[derive(Debug)]
-enum TypedHeader {
-    Number(i32),
-    Str(String),
+// Splits a request line into exactly its method, target and version tokens
+fn request_line_tokens(line: &str) -> Option<(&str, &str, HttpVersion)> {
+    let mut tokens = line.split_ascii_whitespace();
+    let (method, target, version) = (tokens.next()?, tokens.next()?, tokens.next()?);
+    if tokens.next().is_some() {
+        return None;
+    }
+    let (major, minor) = version.strip_prefix("HTTP/")?.split_once('.')?;
+    let digit = |s: &str| match s.as_bytes() {
+        [d] if d.is_ascii_digit() => Some(d - b'0'),
+        _ => None,
+    };
+    let version = HttpVersion {
+        major: digit(major)?,
+        minor: digit(minor)?,
+    };
+    Some((method, target, version))
 }
+
 struct HttpRequest<'a> {
-    method: String,
+    method: Method,
+    // The request-target as sent, `path` and `query` are its decoded parts
+    target: String,
+    // The request line and headers as received, up to the blank line
+    head: String,
     path: String,
-    headers: HashMap<String, TypedHeader>,
+    query: HashMap<String, String>,
+    // Set when a proxy sent the target in absolute-form, e.g. `http://host/path`
+    scheme: Option<String>,
+    authority: Option<String>,
+    version: HttpVersion,
+    // Keyed by lowercased name, header names are case-insensitive
+    headers: HashMap<String, String>,
+    // Sent with both `Transfer-Encoding` and `Content-Length`. The length is dropped
+    // and the connection closed afterwards, as another hop may have framed it by the
+    // length instead.
+    conflicting_framing: bool,
+    // Parsed for handlers, though none reads cookies yet
+    #[cfg_attr(not(test), allow(dead_code))]
+    cookies: HashMap<String, String>,
     body: Option<Pin<&'a [u8]>>,
 }
 
 trait FromStr {
-    fn from_str<T>(s: &T) -> Option<Self>
+    fn from_str<T>(s: &T, config: &Config) -> Result<Self, HttpResponse>
     where
         T: AsRef<str>,
         Self: Sized;
@@ -138,182 +935,6387 @@ trait FromStr {
 impl<'a> HttpRequest<'a> {
     fn with_body(mut self, body: &'a [u8]) -> Self {
         self.body = Some(Pin::new(body));
-        return self;
+        self
+    }
+
+    // A header read as a number, None when it is missing or not one
+    fn header_u64(&self, name: &str) -> Option<u64> {
+        self.header_str(name)?.parse().ok()
+    }
+
+    // A header's value as sent, whether or not it looks like a number
+    fn header_str(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+
+    // The authority of an absolute-form target takes precedence over `Host`
+    fn host(&self) -> Option<&str> {
+        self.authority
+            .as_deref()
+            .or_else(|| self.header_str("Host"))
+    }
+
+    fn content_length(&self) -> usize {
+        self.header_u64("Content-Length")
+            .and_then(|length| usize::try_from(length).ok())
+            .unwrap_or(0)
     }
+
+    fn is_chunked(&self) -> bool {
+        self.header_str("Transfer-Encoding").is_some_and(|codings| {
+            codings
+                .rsplit(',')
+                .next()
+                .is_some_and(|last| last.trim().eq_ignore_ascii_case(CHUNKED))
+        })
+    }
+
+    // POST, PUT and PATCH bodies must be delimited by a length or by chunked framing
+    fn missing_length(&self) -> bool {
+        matches!(self.method, Method::Post | Method::Put | Method::Patch)
+            && !self.headers.contains_key("content-length")
+            && !self.is_chunked()
+    }
+
+    // The lowercased options listed in `Connection`. Besides `close` and `keep-alive`
+    // they name hop-by-hop headers, which a proxy must not pass on.
+    fn connection_tokens(&self) -> HashSet<String> {
+        self.header_str("Connection")
+            .map(|connection| {
+                connection
+                    .split(',')
+                    .map(|token| token.trim().to_ascii_lowercase())
+                    .filter(|token| !token.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn keep_alive(&self) -> bool {
+        let tokens = self.connection_tokens();
+        if self.conflicting_framing || tokens.contains("close") {
+            false
+        } else if tokens.contains("keep-alive") {
+            true
+        } else {
+            !self.version.closes_by_default()
+        }
+    }
+
+    // Interim responses are only defined from HTTP/1.1 onwards
+    fn expects_continue(&self) -> bool {
+        self.version >= HttpVersion::HTTP_1_1
+            && self
+                .header_str("Expect")
+                .is_some_and(|expect| expect.eq_ignore_ascii_case(EXPECT_CONTINUE))
+    }
+
+    // Splits a `multipart/form-data` body into its parts, None when the body isn't one
+    // or is malformed
+    fn multipart(&self) -> Option<Vec<Part>> {
+        let (media_type, params) = self.header_str("Content-Type")?.split_once(';')?;
+        if !media_type.trim().eq_ignore_ascii_case(FORM_DATA) {
+            return None;
+        }
+        let params = header_params(params);
+        let boundary = params
+            .get("boundary")
+            .filter(|boundary| !boundary.is_empty())?;
+        parse_multipart(self.body?.get_ref(), boundary)
+    }
+}
+
+// One field of a form upload
+struct Part {
+    #[cfg_attr(not(test), allow(dead_code))]
+    name: String,
+    filename: Option<String>,
+    #[cfg_attr(not(test), allow(dead_code))]
+    content_type: Option<String>,
+    body: Vec<u8>,
 }
 
 impl<'a> FromStr for HttpRequest<'a> {
-    fn from_str<T>(s: &T) -> Option<Self>
+    fn from_str<T>(s: &T, config: &Config) -> Result<Self, HttpResponse>
     where
         T: AsRef<str>,
     {
         let string = s.as_ref();
-        let caps = METHOD_RE.captures(string)?;
-        let matching = caps.get(1)?;
-        let method = matching.as_str().to_string();
-        let path = extract_path(s)?;
+        let request_line = string.split("\r\n").next().unwrap_or_default();
+        let (method, raw_target, version) =
+            request_line_tokens(request_line).ok_or(HttpResponse::BadRequest)?;
+        // Messages of another major version are framed differently, e.g. HTTP/2
+        if version.major != 1 {
+            return Err(HttpResponse::HttpVersionNotSupported);
+        }
+        // Only visible ASCII may appear in the method and target, a raw control byte
+        // could otherwise end up in a response header or a file name
+        let visible = |token: &str| token.bytes().all(|byte| byte.is_ascii_graphic());
+        if !visible(method) || !visible(raw_target) {
+            return Err(HttpResponse::BadRequest);
+        }
+        let method = Method::parse(method).ok_or(HttpResponse::NotImplemented)?;
+        let raw_target = raw_target.to_string();
+        if raw_target.len() > config.max_uri_bytes {
+            return Err(HttpResponse::UriTooLong);
+        }
+        // The stored path drops the leading slash of the target
+        let (scheme, authority, target) = if let Some(target) = raw_target.strip_prefix('/') {
+            (None, None, target.to_string())
+        } else if let Some(caps) = ABSOLUTE_TARGET_RE.captures(&raw_target) {
+            let scheme = caps[1].to_ascii_lowercase();
+            if scheme != "http" && scheme != "https" {
+                return Err(HttpResponse::BadRequest);
+            }
+            let target = caps[3].strip_prefix('/').unwrap_or(&caps[3]).to_string();
+            (Some(scheme), Some(caps[2].to_string()), target)
+        } else if raw_target == ASTERISK_TARGET && method == Method::Options {
+            // Asterisk-form asks about the server as a whole rather than a resource
+            (None, None, raw_target.clone())
+        } else {
+            return Err(HttpResponse::BadRequest);
+        };
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, parse_query(query)),
+            None => (target.as_str(), HashMap::new()),
+        };
+        let path = percent_decode(path)
+            .and_then(|path| normalize_path(&path))
+            .ok_or(HttpResponse::BadRequest)?;
         let mut headers = HashMap::new();
-        for cap in HEADERS_RE.captures_iter(string) {
+        let mut cookies = HashMap::new();
+        // Values of headers that may appear only once, by lowercased name
+        let mut singletons: HashMap<String, &str> = HashMap::new();
+        for line in string.split("\r\n").skip(1) {
+            let Some(cap) = HEADERS_RE.captures(line) else {
+                continue;
+            };
             if let (Some(key_match), Some(value_match)) = (cap.get(1), cap.get(2)) {
-                let key = key_match.as_str().trim();
+                // `Content-Length : 3` could be read as a length by us and skipped by a
+                // proxy, or the other way around
+                if key_match.as_str().contains(char::is_whitespace) {
+                    return Err(HttpResponse::BadRequest);
+                }
+                let key = key_match.as_str();
                 let value = value_match.as_str().trim();
+                if SINGLETON_HEADERS
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(key))
+                {
+                    // Conflicting copies could make us and a proxy disagree on where
+                    // the request ends
+                    match singletons.insert(key.to_ascii_lowercase(), value) {
+                        Some(previous) if previous != value => {
+                            return Err(HttpResponse::BadRequest)
+                        }
+                        _ => {}
+                    }
+                }
+                // A length we can't read would leave the body to be parsed as the
+                // next request
+                if key.eq_ignore_ascii_case("Content-Length") && value.parse::<u64>().is_err() {
+                    return Err(HttpResponse::BadRequest);
+                }
+                if key.eq_ignore_ascii_case("Cookie") {
+                    parse_cookies(value, &mut cookies);
+                }
 
-                headers.insert(
-                    key.to_string(),
-                    if let Ok(int_value) = value.parse::<i32>() {
-                        TypedHeader::Number(int_value)
-                    } else {
-                        TypedHeader::Str(value.to_string())
-                    },
-                );
+                headers.insert(key.to_ascii_lowercase(), value.to_string());
             }
         }
 
-        Some(HttpRequest {
+        let conflicting_framing =
+            headers.contains_key("transfer-encoding") && headers.remove("content-length").is_some();
+        Ok(HttpRequest {
             method,
+            target: raw_target,
+            head: string.to_string(),
             path,
+            query,
+            scheme,
+            authority,
+            version,
             headers,
+            conflicting_framing,
+            cookies,
             body: None,
         })
     }
 }
 
-fn get_arg(a: &'static str) -> Option<String> {
-    let mut args = std::env::args();
-    while let Some(arg) = args.next() {
-        if arg.as_str() == a {
-            return args.next();
-        }
-    }
-    None
+// Parses `a=1&b=2`, a name without `=` maps to an empty value
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((name, value)) => (decode_query_part(name), decode_query_part(value)),
+            None => (decode_query_part(pair), String::new()),
+        })
+        .collect()
 }
 
-fn file_contents(path: &PathBuf) -> Result<Vec<u8>, std::io::Error> {
-    return match std::fs::metadata(&path) {
-        Ok(metadata) => {
-            if metadata.is_file() {
-                let mut file = File::open(path)?;
-                let mut contents = Vec::new();
-                file.read_to_end(&mut contents)?;
-                Ok(contents)
-            } else {
-                Err(io::Error::new(
-                    io::ErrorKind::NotFound,
-                    format!("the file was not found at path {:?}", path),
-                ))
+// Collapses repeated slashes and resolves `.` and `..` segments, keeping a trailing
+// slash. None when `..` would climb above the root.
+fn normalize_path(path: &str) -> Option<String> {
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
             }
+            segment => segments.push(segment),
         }
-        Err(err) => Err(err),
-    };
+    }
+    let mut normalized = segments.join("/");
+    if path.ends_with('/') && !normalized.is_empty() {
+        normalized.push('/');
+    }
+    Some(normalized)
 }
 
-fn write_file(path: &PathBuf, data: &[u8]) -> Result<usize, std::io::Error> {
-    let mut new_file = File::create(path)?;
-    new_file.write_all(data).unwrap();
-    new_file.flush().unwrap();
-    Ok(data.len())
+// Query components use `+` for spaces, malformed escapes are kept as written
+fn decode_query_part(part: &str) -> String {
+    let part = part.replace('+', " ");
+    percent_decode(&part).unwrap_or(part)
 }
 
-fn process_stream(stream: &mut TcpStream) -> io::Result<(Vec<u8>, usize)> {
-    let mut buffer = Vec::new();
-    let mut temp_buf = [0; 1024]; // Temporary buffer for each read
-    let mut _body_start = 0;
-    loop {
-        let bytes_read = stream.read(&mut temp_buf)?;
-        if bytes_read == 0 {
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "End of stream",
-            ));
+// Decodes `%XX` escapes, failing on a truncated escape or a result that isn't UTF-8
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
         }
-        buffer.extend_from_slice(&temp_buf[..bytes_read]);
+    }
+    String::from_utf8(decoded).ok()
+}
 
-        if let Some(pos) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
-            _body_start = pos + 4; // The body starts after the "\r\n\r\n"
-            break;
+// Parses `name=value; name2=value2`, skipping pairs without a `=`
+fn parse_cookies(header: &str, cookies: &mut HashMap<String, String>) {
+    for pair in header.split(';') {
+        if let Some((name, value)) = pair.split_once('=') {
+            let name = name.trim();
+            if !name.is_empty() {
+                cookies.insert(name.to_string(), value.trim().to_string());
+            }
         }
     }
-    Ok((buffer, _body_start))
 }
 
-fn create_response(req: &HttpRequest) -> HttpResponse {
-    let mut response = HttpResponse::NotFound;
-    let HttpRequest {
-        method,
-        path,
-        headers,
-        body,
-    } = req;
-    if method == GET {
-        if path.is_empty() {
-            // Stage 2
-            response = HttpResponse::Ok(None);
-        } else if let Some(echo) = extract_path_echo(&path) {
-            // Stage 4
-            response = HttpResponse::Ok(Some(echo));
-        } else if path == USER_AGENT_PATH {
-            match headers.get("User-Agent") {
-                Some(TypedHeader::Str(user_agent)) => {
-                    response = HttpResponse::Ok(Some(user_agent.to_string()));
-                }
-                _ => {}
-            }
-        } else if path.contains(FILES_PATH) {
-            // Stage 7
-            if let (Some(dir_name), Some(file_name)) =
-                (get_arg(DIR_PATH), extract_path_filename(&path))
-            {
-                let mut file_path = PathBuf::from(dir_name);
-                file_path.push(&file_name);
-                if let Ok(contents) = file_contents(&file_path) {
-                    response = HttpResponse::OkStream(Some(contents));
-                }
-            }
+// Parses the `; key=value` parameters after a header's main value, keys are lowercased
+// and quoted values unescaped
+fn header_params(params: &str) -> HashMap<String, String> {
+    let mut parsed = HashMap::new();
+    let mut chars = params.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace() || *c == ';').is_some() {}
+        let name: String = chars.by_ref().take_while(|c| *c != '=').collect();
+        if name.is_empty() {
+            return parsed;
         }
-    } else if method == POST {
-        // Stage 8
-        if path.contains(FILES_PATH) {
-            if let (Some(dir_name), Some(file_name), Some(data)) =
-                (get_arg(DIR_PATH), extract_path_filename(&path), body)
-            {
-                let mut file_path = PathBuf::from(dir_name);
-                file_path.push(&file_name);
-                if let Ok(_written_bytes) = write_file(&file_path, &data) {
-                    response = HttpResponse::Created;
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => value.extend(chars.next()),
+                    c => value.push(c),
                 }
             }
+            // Skip anything between the closing quote and the next parameter
+            while chars.next_if(|c| *c != ';').is_some() {}
+        } else {
+            value = chars.by_ref().take_while(|c| *c != ';').collect();
         }
+        parsed.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
     }
-    response
 }
-fn main() {
-    println!("Logs from program will appear here!");
-    let listener = TcpListener::bind("127.0.0.1:4221").unwrap();
-    for stream in listener.incoming() {
-        // Stage 6 (Multi connection server)
-        thread::spawn(|| {
-            match stream {
-                Ok(mut stream) => {
-                    println!("Accepted new connection"); // Stage 1
-                    if let Ok((buf, body_pos)) = process_stream(&mut stream) {
-                        let req_str = String::from_utf8_lossy(&buf[..body_pos]);
-                        let response = match HttpRequest::from_str(&req_str)
-                            .map(|req| req.with_body(&buf[body_pos..]))
-                        {
-                            Some(req) => create_response(&req),
-                            _ => {
-                                println!("Server does not support the http request {req_str}");
-                                HttpResponse::BadRequest
-                            }
-                        };
-                        stream.write_all(&response.into_response()).unwrap();
-                        stream.flush().unwrap(); // Flush the stream
-                    }
-                }
-                Err(e) => {
-                    println!("error: {}", e);
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+// Walks the `--boundary` delimited parts of a form body up to the closing
+// `--boundary--`, reading each part's headers for its name, filename and type
+fn parse_multipart(body: &[u8], boundary: &str) -> Option<Vec<Part>> {
+    let delimiter = format!("--{}", boundary);
+    // Anything before the first delimiter is preamble
+    let mut rest = &body[find_bytes(body, delimiter.as_bytes())? + delimiter.len()..];
+    let mut parts = Vec::new();
+    loop {
+        if rest.starts_with(b"--") {
+            return Some(parts);
+        }
+        rest = rest.strip_prefix(b"\r\n")?;
+        let headers_end = find_bytes(rest, b"\r\n\r\n")?;
+        let headers = std::str::from_utf8(&rest[..headers_end]).ok()?;
+        rest = &rest[headers_end + 4..];
+
+        let mut disposition = None;
+        let mut content_type = None;
+        for line in headers.split("\r\n") {
+            let (name, value) = line.split_once(':')?;
+            if name.trim().eq_ignore_ascii_case("Content-Disposition") {
+                let (kind, params) = value.split_once(';').unwrap_or((value, ""));
+                if kind.trim().eq_ignore_ascii_case("form-data") {
+                    disposition = Some(header_params(params));
                 }
+            } else if name.trim().eq_ignore_ascii_case("Content-Type") {
+                content_type = Some(value.trim().to_string());
             }
+        }
+        let mut disposition = disposition?;
+
+        let close = format!("\r\n{}", delimiter);
+        let body_end = find_bytes(rest, close.as_bytes())?;
+        parts.push(Part {
+            name: disposition.remove("name")?,
+            filename: disposition.remove("filename"),
+            content_type,
+            body: rest[..body_end].to_vec(),
         });
+        rest = &rest[body_end + close.len()..];
+    }
+}
+
+// Command-line options, parsed at startup and again on SIGHUP. A connection is served
+// with the one current when it was accepted, passed down by reference; only code with
+// no connection at hand, like logging, reads `current_config()`.
+struct Config {
+    bind: String,
+    bind_unix: Option<String>,
+    port: u16,
+    directory: Option<String>,
+    // `--vhost host=dir`, in the order given
+    vhosts: Vec<(String, String)>,
+    // `--redirect /from=/to`, in the order given
+    redirects: Vec<(String, String)>,
+    absolute_redirects: bool,
+    index_file: Option<String>,
+    list_directories: bool,
+    no_debug_routes: bool,
+    no_follow_symlinks: bool,
+    weak_etags: bool,
+    reuse_port: bool,
+    backlog: u32,
+    check: bool,
+    deny: Vec<String>,
+    allow: Vec<String>,
+    cache_control: Vec<(String, String)>,
+    checksum_trailer: bool,
+    spa_fallback: bool,
+    spa_api_prefix: String,
+    templating: bool,
+    template_vars: Vec<(String, String)>,
+    cors_origin: Option<String>,
+    auth: Option<String>,
+    rate_limit: Option<usize>,
+    trust_proxy: bool,
+    default_headers: Vec<(String, String)>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    admin_port: Option<u16>,
+    log_level: LogLevel,
+    access_log: bool,
+    // Size of each socket read, `--read-buffer` trades syscalls for memory per connection
+    read_buffer_bytes: usize,
+    max_uri_bytes: usize,
+    max_body_bytes: Option<usize>,
+    cache_bytes: usize,
+    gzip_min_size: usize,
+    keepalive_timeout: Duration,
+    keepalive_max: usize,
+    request_timeout: Duration,
+    // Unlimited unless `--workers` is given
+    workers: Option<usize>,
+    queue_capacity: usize,
+    max_connections: Option<usize>,
+}
+
+impl Config {
+    fn from_args() -> Result<Config, String> {
+        let cli: Vec<String> = std::env::args().skip(1).collect();
+        let mut args = match Args(cli.clone()).value(CONFIG_FILE_ARG) {
+            Some(path) => config_file_args(path)?,
+            None => Vec::new(),
+        };
+        // Later occurrences win, so the command line overrides the file
+        args.extend(cli);
+        Config::parse(args)
+    }
+
+    fn parse(args: Vec<String>) -> Result<Config, String> {
+        let args = Args(args);
+        let read_buffer_bytes = args.number(READ_BUFFER_ARG, DEFAULT_READ_BUFFER_BYTES)?;
+        if !READ_BUFFER_RANGE.contains(&read_buffer_bytes) {
+            return Err(format!(
+                "{} must be between {} and {} bytes, got {}",
+                READ_BUFFER_ARG,
+                READ_BUFFER_RANGE.start(),
+                READ_BUFFER_RANGE.end(),
+                read_buffer_bytes
+            ));
+        }
+        let log_level = match args.value(LOG_LEVEL_ARG) {
+            Some(level) => LogLevel::parse(level)
+                .ok_or_else(|| format!("{} must be debug, info, warn or error", LOG_LEVEL_ARG))?,
+            None => LogLevel::Info,
+        };
+        let quiet = args.flag(QUIET_ARG);
+        let access_log = args.flag(ACCESS_LOG_ARG) || (!quiet && log_level <= LogLevel::Info);
+        let log_level = if quiet { LogLevel::Error } else { log_level };
+        let default_headers = args
+            .values(HEADER_ARG)
+            .into_iter()
+            .map(|header| match header.split_once(':') {
+                Some((name, value)) if !name.trim().is_empty() => {
+                    Ok((name.trim().to_string(), value.trim().to_string()))
+                }
+                _ => Err(format!(
+                    "{} must look like \"Name: Value\", got {:?}",
+                    HEADER_ARG, header
+                )),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Config {
+            bind: args.value(BIND_ARG).unwrap_or(DEFAULT_BIND).to_string(),
+            bind_unix: args.value(BIND_UNIX_ARG).map(str::to_string),
+            port: args.port(PORT_ARG)?.unwrap_or(DEFAULT_PORT),
+            directory: args.value(DIR_PATH).map(str::to_string),
+            vhosts: args.pairs(VHOST_ARG)?,
+            redirects: args.pairs(REDIRECT_ARG)?,
+            absolute_redirects: args.flag(ABSOLUTE_REDIRECTS_ARG),
+            index_file: args.value(INDEX_FILE_ARG).map(str::to_string),
+            list_directories: args.flag(LIST_DIRS_ARG),
+            no_debug_routes: args.flag(NO_DEBUG_ROUTES_ARG),
+            no_follow_symlinks: args.flag(NO_FOLLOW_SYMLINKS_ARG),
+            weak_etags: args.flag(WEAK_ETAGS_ARG),
+            reuse_port: args.flag(REUSE_PORT_ARG),
+            backlog: args.number(BACKLOG_ARG, DEFAULT_BACKLOG)?,
+            check: args.flag(CHECK_ARG),
+            deny: args
+                .values(DENY_ARG)
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+            allow: args
+                .values(ALLOW_ARG)
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+            cache_control: args.pairs(CACHE_CONTROL_ARG)?,
+            checksum_trailer: args.flag(CHECKSUM_TRAILER_ARG),
+            spa_fallback: args.flag(SPA_FALLBACK_ARG),
+            spa_api_prefix: args
+                .value(SPA_API_PREFIX_ARG)
+                .unwrap_or(DEFAULT_SPA_API_PREFIX)
+                .trim_matches('/')
+                .to_string(),
+            templating: args.flag(TEMPLATING_ARG),
+            template_vars: args.pairs(TEMPLATE_VAR_ARG)?,
+            cors_origin: args.value(CORS_ORIGIN_ARG).map(str::to_string),
+            auth: args.value(AUTH_ARG).map(str::to_string),
+            rate_limit: args.optional_number(RATE_LIMIT_ARG)?,
+            trust_proxy: args.flag(TRUST_PROXY_ARG),
+            default_headers,
+            tls_cert: args.value(TLS_CERT_ARG).map(str::to_string),
+            tls_key: args.value(TLS_KEY_ARG).map(str::to_string),
+            admin_port: args.port(ADMIN_PORT_ARG)?,
+            log_level,
+            access_log,
+            read_buffer_bytes,
+            max_uri_bytes: args.number(MAX_URI_ARG, DEFAULT_MAX_URI_BYTES)?,
+            max_body_bytes: args.optional_number(MAX_BODY_ARG)?,
+            cache_bytes: args.number(CACHE_BYTES_ARG, 0)?,
+            gzip_min_size: args.number(GZIP_MIN_SIZE_ARG, DEFAULT_GZIP_MIN_SIZE)?,
+            keepalive_timeout: Duration::from_secs(
+                args.number(KEEPALIVE_TIMEOUT_ARG, DEFAULT_KEEPALIVE_TIMEOUT.as_secs())?,
+            ),
+            keepalive_max: args.number(KEEPALIVE_MAX_ARG, DEFAULT_KEEPALIVE_MAX)?,
+            request_timeout: Duration::from_secs(
+                args.number(REQUEST_TIMEOUT_ARG, DEFAULT_REQUEST_TIMEOUT.as_secs())?,
+            ),
+            workers: args
+                .optional_number(WORKERS_ARG)?
+                .filter(|&workers| workers > 0),
+            queue_capacity: args.number(QUEUE_CAPACITY_ARG, DEFAULT_QUEUE_CAPACITY)?,
+            max_connections: args.optional_number(MAX_CONNECTIONS_ARG)?,
+        })
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::parse(Vec::new()).expect("the defaults are valid")
+    }
+}
+
+// The arguments in a `--config` file. Blank lines and those starting with `#` are
+// skipped, the value is the rest of the line after the flag.
+fn config_file_args(path: &str) -> Result<Vec<String>, String> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    let mut args = Vec::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(char::is_whitespace) {
+            Some((flag, value)) => {
+                args.push(flag.to_string());
+                args.push(value.trim().to_string());
+            }
+            None => args.push(line.to_string()),
+        }
+    }
+    Ok(args)
+}
+
+// The configuration new connections are served with
+fn current_config() -> Arc<Config> {
+    CONFIG.read().unwrap().clone()
+}
+
+// Parses the arguments again and swaps the result in, keeping the old configuration
+// when they are invalid. Listener, TLS and worker settings only apply at startup.
+fn reload_config() {
+    match Config::from_args() {
+        Ok(config) => {
+            *CONFIG.write().unwrap() = Arc::new(config);
+            log_info!("Configuration reloaded");
+        }
+        Err(e) => log_error!("error: not reloading the configuration: {}", e),
+    }
+}
+
+// The raw arguments, without the program name
+struct Args(Vec<String>);
+
+impl Args {
+    // The value following the last occurrence of `name`
+    fn value(&self, name: &str) -> Option<&str> {
+        self.values(name).pop()
+    }
+
+    // Every value given for a repeatable flag, in order
+    fn values(&self, name: &str) -> Vec<&str> {
+        self.0
+            .windows(2)
+            .filter(|pair| pair[0] == name)
+            .map(|pair| pair[1].as_str())
+            .collect()
+    }
+
+    // Whether a flag that takes no value was passed
+    fn flag(&self, name: &str) -> bool {
+        self.0.iter().any(|arg| arg == name)
+    }
+
+    fn optional_number<T: std::str::FromStr>(&self, name: &str) -> Result<Option<T>, String> {
+        self.value(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| format!("{} must be a number, got {}", name, value))
+            })
+            .transpose()
+    }
+
+    // A port to listen on. 0 would have the OS pick one that nobody knows to connect to.
+    fn port(&self, name: &str) -> Result<Option<u16>, String> {
+        self.value(name)
+            .map(|value| match value.parse::<u16>() {
+                Ok(port) if port > 0 => Ok(port),
+                _ => Err(format!(
+                    "{} must be a port between 1 and 65535, got {}",
+                    name, value
+                )),
+            })
+            .transpose()
+    }
+
+    fn number<T: std::str::FromStr>(&self, name: &str, default: T) -> Result<T, String> {
+        Ok(self.optional_number(name)?.unwrap_or(default))
+    }
+
+    // Repeatable `name=value` arguments
+    fn pairs(&self, name: &str) -> Result<Vec<(String, String)>, String> {
+        self.values(name)
+            .into_iter()
+            .map(|pair| match pair.split_once('=') {
+                Some((key, value)) => Ok((key.to_string(), value.to_string())),
+                None => Err(format!("{} must look like name=value, got {}", name, pair)),
+            })
+            .collect()
+    }
+}
+
+// Directory served for the request, a `--vhost host=dir` matching its Host wins
+// over `--directory`
+fn site_dir(req: &HttpRequest, config: &Config) -> Option<String> {
+    let host = req.host().and_then(parse_host).map(|(name, _)| name);
+    let vhost = config.vhosts.iter().find_map(|(name, dir_name)| {
+        host.as_ref()
+            .is_some_and(|host| host.eq_ignore_ascii_case(name))
+            .then(|| dir_name.clone())
+    });
+    vhost.or_else(|| config.directory.clone())
+}
+
+// Splits a Host header into its name and optional port. A bracketed IPv6 literal
+// loses its brackets, a trailing dot is dropped. None when the header is malformed.
+fn parse_host(host: &str) -> Option<(String, Option<u16>)> {
+    let host = host.trim();
+    let (name, port) = match host.strip_prefix('[') {
+        Some(literal) => {
+            let (address, rest) = literal.split_once(']')?;
+            address.parse::<std::net::Ipv6Addr>().ok()?;
+            match rest {
+                "" => (address, None),
+                rest => (address, Some(rest.strip_prefix(':')?)),
+            }
+        }
+        None => match host.split_once(':') {
+            Some((name, port)) => (name, Some(port)),
+            None => (host, None),
+        },
+    };
+    let port = match port {
+        Some(port) if port.bytes().all(|b| b.is_ascii_digit()) => Some(port.parse().ok()?),
+        Some(_) => return None,
+        None => None,
+    };
+    let name = name.trim_end_matches('.');
+    if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c == '/' || c == '@') {
+        return None;
+    }
+    Some((name.to_string(), port))
+}
+
+// Why a file could not be read or written, decides the status of the response
+#[derive(Debug)]
+enum ServerError {
+    NotFound,
+    Forbidden,
+    BadRequest,
+    Io(io::Error),
+}
+
+impl ServerError {
+    // The status a request failing this way is answered with
+    fn status(&self) -> u16 {
+        match self {
+            ServerError::NotFound => 404,
+            ServerError::Forbidden => 403,
+            ServerError::BadRequest => 400,
+            ServerError::Io(_) => 500,
+        }
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound | io::ErrorKind::NotADirectory => ServerError::NotFound,
+            io::ErrorKind::PermissionDenied => ServerError::Forbidden,
+            io::ErrorKind::InvalidInput | io::ErrorKind::InvalidFilename => ServerError::BadRequest,
+            _ => ServerError::Io(e),
+        }
+    }
+}
+
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerError::NotFound => write!(f, "not found"),
+            ServerError::Forbidden => write!(f, "permission denied"),
+            ServerError::BadRequest => write!(f, "invalid file name"),
+            ServerError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+// Reads a regular file along with its metadata. Both come from the same open
+// handle, so the size and modification time always describe the bytes returned.
+async fn file_contents(path: &Path, config: &Config) -> Result<(Vec<u8>, Metadata), ServerError> {
+    read_opened(path, open_file(path).await?, config).await
+}
+
+// Opens `path`, which may be a directory, and takes the metadata off the handle
+async fn open_file(path: &Path) -> Result<(fs::File, Metadata), ServerError> {
+    let file = fs::File::open(path).await?;
+    let metadata = file.metadata().await?;
+    Ok((file, metadata))
+}
+
+// Reads a file opened by `open_file`, through the cache when its `modified` allows
+async fn read_opened(
+    path: &Path,
+    (mut file, metadata): (fs::File, Metadata),
+    config: &Config,
+) -> Result<(Vec<u8>, Metadata), ServerError> {
+    // Directories are only served as listings
+    if !metadata.is_file() {
+        return Err(ServerError::NotFound);
+    }
+    let modified = metadata.modified()?;
+    if let Some(contents) = FILE_CACHE.lock().unwrap().get(path, modified) {
+        return Ok((contents, metadata));
+    }
+    let mut contents = Vec::with_capacity(metadata.len() as usize);
+    file.read_to_end(&mut contents).await?;
+    FILE_CACHE
+        .lock()
+        .unwrap()
+        .insert(path, modified, &contents, config.cache_bytes);
+    Ok((contents, metadata))
+}
+
+// Recently served small files, evicted least recently used first once their total
+// size passes `--cache-bytes`. Caching is off without the flag.
+struct FileCache {
+    entries: HashMap<PathBuf, CachedFile>,
+    total_bytes: usize,
+    // Bumped on every lookup to order entries by use
+    clock: u64,
+}
+
+struct CachedFile {
+    contents: Vec<u8>,
+    modified: SystemTime,
+    last_used: u64,
+}
+
+impl FileCache {
+    fn new() -> Self {
+        FileCache {
+            entries: HashMap::new(),
+            total_bytes: 0,
+            clock: 0,
+        }
+    }
+
+    // The cached bytes, unless the file was modified since they were read
+    fn get(&mut self, path: &Path, modified: SystemTime) -> Option<Vec<u8>> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(path)?;
+        if entry.modified == modified {
+            entry.last_used = self.clock;
+            return Some(entry.contents.clone());
+        }
+        self.remove(path);
+        None
+    }
+
+    // `capacity` is the `--cache-bytes` of the connection reading the file
+    fn insert(&mut self, path: &Path, modified: SystemTime, contents: &[u8], capacity: usize) {
+        if contents.len() > capacity.min(CACHE_MAX_FILE_BYTES) {
+            return;
+        }
+        self.remove(path);
+        while self.total_bytes + contents.len() > capacity {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(path, _)| path.clone())
+            else {
+                break;
+            };
+            self.remove(&oldest);
+        }
+        self.total_bytes += contents.len();
+        let entry = CachedFile {
+            contents: contents.to_vec(),
+            modified,
+            last_used: self.clock,
+        };
+        self.entries.insert(path.to_path_buf(), entry);
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.entries.remove(path) {
+            self.total_bytes -= entry.contents.len();
+        }
+    }
+}
+
+async fn write_file(path: &Path, data: &[u8]) -> Result<usize, ServerError> {
+    let mut new_file = fs::File::create(path).await?;
+    new_file.write_all(data).await?;
+    new_file.flush().await?;
+    Ok(data.len())
+}
+
+async fn append_file(path: &Path, data: &[u8]) -> Result<usize, ServerError> {
+    let mut file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .await?;
+    file.write_all(data).await?;
+    file.flush().await?;
+    Ok(data.len())
+}
+
+// Overwrites `data.len()` bytes of an existing file starting at `offset`
+async fn patch_file(path: &Path, offset: u64, data: &[u8]) -> Result<usize, ServerError> {
+    let mut file = OpenOptions::new().write(true).open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    file.write_all(data).await?;
+    file.flush().await?;
+    Ok(data.len())
+}
+
+// Start offset of a `bytes start-end/total` Content-Range, None unless the range
+// spans exactly `len` bytes. The total may be `*`.
+fn patch_offset(range: &str, len: usize) -> Option<u64> {
+    let (span, total) = range.trim().strip_prefix("bytes ")?.split_once('/')?;
+    if total != "*" {
+        total.parse::<u64>().ok()?;
+    }
+    let (start, end) = span.split_once('-')?;
+    let (start, end): (u64, u64) = (start.parse().ok()?, end.parse().ok()?);
+    (end >= start && end - start + 1 == len as u64).then_some(start)
+}
+
+// Why reading a request stopped short
+enum ReadError {
+    // The connection failed or closed, there is nobody to answer
+    Io(io::Error),
+    // The request is unacceptable and gets this response before the connection closes
+    Rejected(HttpResponse),
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self {
+        ReadError::Io(e)
+    }
+}
+
+// Reads into `temp_buf`, the stream ending is an error since more was expected
+async fn read_chunk<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    temp_buf: &mut [u8],
+) -> io::Result<usize> {
+    match stream.read(temp_buf).await? {
+        0 => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "End of stream",
+        )),
+        bytes_read => Ok(bytes_read),
+    }
+}
+
+// Waits for the first byte of the next request unless pipelined bytes are already
+// buffered, so the request deadline only starts once the client is sending. Wakes up
+// every poll interval so an idle connection gives up once the server is shutting down,
+// the keep-alive timeout is left to `reap_idle_connections`.
+async fn await_request<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer: &mut Vec<u8>,
+    config: &Config,
+    server: &ServerState,
+) -> io::Result<()> {
+    if !buffer.is_empty() {
+        return Ok(());
+    }
+    let mut temp_buf = vec![0; config.read_buffer_bytes];
+    loop {
+        match time::timeout(READ_POLL_INTERVAL, read_chunk(stream, &mut temp_buf)).await {
+            Ok(bytes_read) => {
+                buffer.extend_from_slice(&temp_buf[..bytes_read?]);
+                return Ok(());
+            }
+            Err(_elapsed) => {
+                if server.shutting_down() {
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+            }
+        }
+    }
+}
+
+// Runs a read step of a request, giving up with a 408 once `deadline` has passed
+async fn before<T>(
+    deadline: time::Instant,
+    read: impl std::future::Future<Output = Result<T, ReadError>>,
+) -> Result<T, ReadError> {
+    time::timeout_at(deadline, read)
+        .await
+        .unwrap_or(Err(ReadError::Rejected(HttpResponse::RequestTimeout)))
+}
+
+// Reads until `buffer` holds a full header block and returns where the body starts.
+// Bytes already in `buffer` (left over from a pipelined request) are parsed first.
+async fn process_stream<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer: &mut Vec<u8>,
+    config: &Config,
+) -> Result<usize, ReadError> {
+    let mut temp_buf = vec![0; config.read_buffer_bytes]; // Temporary buffer for each read
+    let mut scan_from = 0;
+    loop {
+        let terminator = buffer[scan_from..]
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n");
+        // Header lines must end in CRLF. A bare LF is refused rather than guessed at,
+        // a proxy in front may have split the request differently.
+        let scan_to = terminator.map_or(buffer.len(), |pos| scan_from + pos + 4);
+        if (scan_from..scan_to).any(|i| buffer[i] == b'\n' && (i == 0 || buffer[i - 1] != b'\r')) {
+            return Err(ReadError::Rejected(HttpResponse::BadRequest));
+        }
+        if let Some(pos) = terminator {
+            let body_start = scan_from + pos + 4; // The body starts after the "\r\n\r\n"
+            if body_start > MAX_HEADER_BYTES {
+                break;
+            }
+            return Ok(body_start);
+        }
+        if buffer.len() > MAX_HEADER_BYTES {
+            break;
+        }
+        // Only the new bytes (plus an overlap for a split terminator) need scanning
+        scan_from = buffer.len().saturating_sub(3);
+        let bytes_read = read_chunk(stream, &mut temp_buf).await?;
+        buffer.extend_from_slice(&temp_buf[..bytes_read]);
+    }
+    // Blame the request line when it alone overflows the header block
+    let request_line_done = buffer[..MAX_HEADER_BYTES]
+        .windows(2)
+        .any(|window| window == b"\r\n");
+    Err(ReadError::Rejected(if request_line_done {
+        HttpResponse::HeaderFieldsTooLarge
+    } else {
+        HttpResponse::UriTooLong
+    }))
+}
+
+// Reads until `buffer` holds `body_end` bytes. A client that hangs up before sending
+// everything it declared gets a 400 so the partial body is never acted on.
+async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer: &mut Vec<u8>,
+    body_end: usize,
+    config: &Config,
+) -> Result<(), ReadError> {
+    let mut temp_buf = vec![0; config.read_buffer_bytes];
+    while buffer.len() < body_end {
+        let bytes_read = match read_chunk(stream, &mut temp_buf).await {
+            Ok(bytes_read) => bytes_read,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(ReadError::Rejected(HttpResponse::BadRequest))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        buffer.extend_from_slice(&temp_buf[..bytes_read]);
+    }
+    Ok(())
+}
+
+// Reads until a CRLF shows up at or after `from` and returns its position. A line
+// longer than MAX_CHUNK_LINE_BYTES is refused with a 400.
+async fn read_line<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer: &mut Vec<u8>,
+    from: usize,
+    config: &Config,
+) -> Result<usize, ReadError> {
+    let mut temp_buf = vec![0; config.read_buffer_bytes];
+    loop {
+        if let Some(pos) = buffer[from..]
+            .windows(2)
+            .position(|window| window == b"\r\n")
+        {
+            return Ok(from + pos);
+        }
+        if buffer.len() - from > MAX_CHUNK_LINE_BYTES {
+            return Err(ReadError::Rejected(HttpResponse::BadRequest));
+        }
+        let bytes_read = read_chunk(stream, &mut temp_buf).await?;
+        buffer.extend_from_slice(&temp_buf[..bytes_read]);
+    }
+}
+
+// Decodes a chunked body starting at `body_start` in place, so that afterwards the
+// decoded bytes end at the returned offset and any pipelined bytes follow directly.
+// Once the decoded size would pass `--max-body-bytes` the rest is refused with a 413.
+async fn read_chunked_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer: &mut Vec<u8>,
+    body_start: usize,
+    config: &Config,
+) -> Result<usize, ReadError> {
+    let malformed = || ReadError::Rejected(HttpResponse::BadRequest);
+    let mut decoded = Vec::new();
+    let mut pos = body_start;
+    loop {
+        let line_end = read_line(stream, buffer, pos, config).await?;
+        let size_line = String::from_utf8_lossy(&buffer[pos..line_end]);
+        // Chunk extensions after `;` carry nothing we use
+        let size = size_line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| malformed())?;
+        let max_body = config.max_body_bytes;
+        if max_body.is_some_and(|max| decoded.len().saturating_add(size) > max) {
+            return Err(ReadError::Rejected(HttpResponse::PayloadTooLarge));
+        }
+        pos = line_end + 2;
+        if size == 0 {
+            // Skip the trailer section up to the final empty line
+            loop {
+                let line_end = read_line(stream, buffer, pos, config).await?;
+                let empty = line_end == pos;
+                pos = line_end + 2;
+                if empty {
+                    break;
+                }
+            }
+            break;
+        }
+        let chunk_end = pos.saturating_add(size).saturating_add(2);
+        read_body(stream, buffer, chunk_end, config).await?;
+        if &buffer[pos + size..pos + size + 2] != b"\r\n" {
+            return Err(malformed());
+        }
+        decoded.extend_from_slice(&buffer[pos..pos + size]);
+        pos += size + 2;
+    }
+    let body_end = body_start + decoded.len();
+    buffer.splice(body_start..pos, decoded);
+    Ok(body_end)
+}
+
+// Dispatches a fully read request on its method and path. The connection is never
+// touched here, reading and writing stay with `serve_requests`. The result is a
+// `ResponseBuilder` rather than a bare `HttpResponse` so the headers a route sets
+// (content type, cookies, caching) travel with it until `IntoResponse` serializes it.
+async fn handle_request(req: &HttpRequest<'_>, config: &Config) -> ResponseBuilder {
+    let mut response: ResponseBuilder = HttpResponse::NotFound(None).into();
+    let HttpRequest {
+        method,
+        path,
+        query,
+        body,
+        ..
+    } = req;
+    if !host_acceptable(req) {
+        return HttpResponse::BadRequest.into();
+    }
+    // A route with a bug, to check that a panic fails only its own request
+    #[cfg(test)]
+    if path == "panic" {
+        panic!("deliberate panic in a handler");
+    }
+    if let Some(location) = redirect_for(path, config) {
+        return HttpResponse::Redirect {
+            status: 308,
+            location: absolute_location(req, location, config),
+        }
+        .into();
+    }
+    if config.no_debug_routes && is_debug_route(path) {
+        return not_found_page(req, config).await.into();
+    }
+    // HEAD is routed like GET, its body is dropped when the response is written
+    if matches!(method, Method::Get | Method::Head) {
+        if path.is_empty() {
+            // Stage 2
+            response = match &config.index_file {
+                Some(index_file) => serve_followed(req, None, Path::new(index_file), config)
+                    .await
+                    .unwrap_or_else(|e| HttpResponse::from(e).into()),
+                None => root_response(req, config).await,
+            };
+        } else if let Some(echo) = path.strip_prefix(ECHO_PATH) {
+            // Stage 4
+            // The whole decoded remainder of the path is echoed back
+            response = HttpResponse::Ok(Some(echo.to_string())).into();
+            if worth_compressing(req, "text/plain", echo.len(), config) {
+                response = response.compress(req);
+            }
+        } else if path == METRICS_PATH {
+            response = HttpResponse::Ok(Some(metrics_report())).into();
+        } else if HEALTH_PATHS.contains(&path.as_str()) {
+            response = HttpResponse::Ok(Some(health_report())).into();
+        } else if path == USER_AGENT_PATH {
+            if let Some(user_agent) = req.header_str("User-Agent") {
+                response = HttpResponse::Ok(Some(user_agent.to_string())).into();
+            }
+        } else if path.contains(FILES_PATH) {
+            // Stage 7
+            if !is_authorized(req, config) {
+                response = HttpResponse::Unauthorized.into();
+            } else if let (Some(dir_name), Some(file_name)) =
+                (site_dir(req, config), extract_path_filename(&path))
+            {
+                let Some(file_path) = file_path_in(&dir_name, &file_name) else {
+                    return HttpResponse::BadRequest.into();
+                };
+                if !may_follow(Some(&dir_name), &file_path, config).await {
+                    return not_found_page(req, config).await.into();
+                }
+                // Opened once, whether it turns out a directory or a file to serve
+                let opened = open_file(&file_path).await;
+                let is_dir = opened.as_ref().is_ok_and(|(_, metadata)| metadata.is_dir());
+                if config.list_directories && is_dir {
+                    if let Ok(listing) = directory_listing(&file_path, path, config).await {
+                        response = ResponseBuilder::new(HttpResponse::Ok(Some(listing)))
+                            .content_type("text/html")
+                            .chunked();
+                    }
+                } else if !is_dir && !name_permitted(&file_path, config) {
+                    // Left a 404, as if the file didn't exist
+                } else {
+                    response = match serve_opened(req, &file_path, opened, config).await {
+                        Ok(file_response)
+                            if query
+                                .get(DOWNLOAD_QUERY)
+                                .is_some_and(|download| download == "true") =>
+                        {
+                            file_response.header("Content-Disposition", attachment(&file_path))
+                        }
+                        Ok(file_response) => file_response,
+                        Err(e) => HttpResponse::from(e).into(),
+                    };
+                }
+            }
+        }
+    } else if matches!(method, Method::Post | Method::Put) {
+        // Stage 8
+        if path.contains(FILES_PATH) {
+            if !is_authorized(req, config) {
+                response = HttpResponse::Unauthorized.into();
+            } else if let (Some(dir_name), Some(file_name), Some(data)) =
+                (site_dir(req, config), extract_path_filename(&path), body)
+            {
+                let Some(file_path) = file_path_in(&dir_name, &file_name) else {
+                    return HttpResponse::BadRequest.into();
+                };
+                if !write_preconditions_hold(req, &file_path, config).await {
+                    return HttpResponse::PreconditionFailed.into();
+                }
+                // PUT always replaces the whole file
+                let written = if *method == Method::Post
+                    && query
+                        .get(APPEND_QUERY)
+                        .is_some_and(|append| append == "true")
+                {
+                    append_file(&file_path, data).await
+                } else {
+                    write_file(&file_path, data).await
+                };
+                response = match written {
+                    Ok(_written_bytes) => HttpResponse::Created.into(),
+                    Err(e) => HttpResponse::from(e).into(),
+                };
+            }
+        } else if *method == Method::Post && path == UPLOAD_PATH {
+            response = if !is_authorized(req, config) {
+                HttpResponse::Unauthorized.into()
+            } else {
+                save_uploads(req, config).await.into()
+            };
+        }
+    } else if *method == Method::Patch && path.contains(FILES_PATH) {
+        if !is_authorized(req, config) {
+            response = HttpResponse::Unauthorized.into();
+        } else if let (Some(dir_name), Some(file_name), Some(data)) =
+            (site_dir(req, config), extract_path_filename(&path), body)
+        {
+            let Some(file_path) = file_path_in(&dir_name, &file_name) else {
+                return HttpResponse::BadRequest.into();
+            };
+            let offset = req
+                .header_str("Content-Range")
+                .and_then(|range| patch_offset(range, data.len()));
+            response = match offset {
+                None => HttpResponse::BadRequest.into(),
+                // A missing file stays a 404, writing past its end extends it
+                Some(offset) => match patch_file(&file_path, offset, data).await {
+                    Ok(_written_bytes) => HttpResponse::NoContent.into(),
+                    Err(e) => HttpResponse::from(e).into(),
+                },
+            };
+        }
+    } else if *method == Method::Trace {
+        // Echoes the request line and headers as received, never the body
+        response = ResponseBuilder::new(HttpResponse::Ok(Some(req.head.clone())))
+            .content_type("message/http");
+    } else if *method == Method::Options && req.target == ASTERISK_TARGET {
+        response = ResponseBuilder::new(HttpResponse::Ok(None)).header("Allow", SERVER_METHODS);
+    } else if let Some(allow) = route_methods(path).filter(|_| *method == Method::Options) {
+        // The CORS allow headers, if any, are attached by `with_cors`
+        response = ResponseBuilder::new(HttpResponse::NoContent).header("Allow", allow);
+    } else if *method == Method::Options && config.cors_origin.is_some() {
+        // CORS preflight, the allow headers are attached by `with_cors`
+        response = HttpResponse::NoContent.into();
+    }
+    if let HttpResponse::NotFound(None) = response.response {
+        response = match spa_fallback(req, config).await {
+            Some(index) => index,
+            None => not_found_page(req, config).await.into(),
+        };
+    }
+    response
+}
+
+// `/` without `--index-file` is served like `/files/`: the directory's `index.html`,
+// else its listing when listings are enabled. Without either it is an empty 200.
+async fn root_response(req: &HttpRequest<'_>, config: &Config) -> ResponseBuilder {
+    let Some(dir_name) = site_dir(req, config) else {
+        return HttpResponse::Ok(None).into();
+    };
+    if !is_authorized(req, config) {
+        return HttpResponse::Unauthorized.into();
+    }
+    let index = Path::new(&dir_name).join(INDEX_PAGE);
+    match serve_followed(req, Some(&dir_name), &index, config).await {
+        Err(ServerError::NotFound) => {}
+        served => return served.unwrap_or_else(|e| HttpResponse::from(e).into()),
+    }
+    if config.list_directories {
+        // Entries link to their `/files/` paths, which serve them
+        if let Ok(listing) = directory_listing(Path::new(&dir_name), FILES_PATH, config).await {
+            return ResponseBuilder::new(HttpResponse::Ok(Some(listing)))
+                .content_type("text/html")
+                .chunked();
+        }
+    }
+    HttpResponse::Ok(None).into()
+}
+
+// HTTP/1.1 requests must identify the target host. Its port is not compared with the
+// one we listen on, behind a proxy or a port mapping it names the public one.
+fn host_acceptable(req: &HttpRequest) -> bool {
+    match req.host().map(parse_host) {
+        None => req.version < HttpVersion::HTTP_1_1,
+        Some(host) => host.is_some(),
+    }
+}
+
+// A file being written by a streamed upload. Dropping it before `finish`, e.g. when
+// the client hangs up or times out, undoes the partial write.
+struct Upload {
+    path: PathBuf,
+    // The replacement is written next to the file and renamed over it once complete
+    partial: Option<PathBuf>,
+    // Where an appended upload started, so the file can be cut back to it
+    append_from: Option<u64>,
+    done: bool,
+}
+
+impl Upload {
+    fn new(path: PathBuf, append: bool) -> Self {
+        let partial = (!append).then(|| {
+            let id = NEXT_UPLOAD_ID.fetch_add(1, Ordering::Relaxed);
+            let mut partial = path.as_os_str().to_owned();
+            partial.push(format!(".upload-{}", id));
+            PathBuf::from(partial)
+        });
+        Upload {
+            path,
+            partial,
+            append_from: None,
+            done: false,
+        }
+    }
+
+    async fn open(&mut self) -> io::Result<fs::File> {
+        match &self.partial {
+            Some(partial) => fs::File::create(partial).await,
+            None => {
+                let file = OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(&self.path)
+                    .await?;
+                self.append_from = Some(file.metadata().await?.len());
+                Ok(file)
+            }
+        }
+    }
+
+    async fn finish(mut self) -> io::Result<()> {
+        if let Some(partial) = &self.partial {
+            fs::rename(partial, &self.path).await?;
+        }
+        self.done = true;
+        Ok(())
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        if let Some(partial) = &self.partial {
+            let _ = std::fs::remove_file(partial);
+        } else if let Some(len) = self.append_from {
+            if let Ok(file) = std::fs::OpenOptions::new().write(true).open(&self.path) {
+                let _ = file.set_len(len);
+            }
+        }
+    }
+}
+
+// The file a POST or PUT to `/files` can be streamed into without buffering its
+// body. Anything the handler would refuse, and bodies that are chunked or encoded,
+// take the buffered path instead so it answers them as usual.
+async fn upload_target(req: &HttpRequest<'_>, config: &Config) -> Option<Upload> {
+    let streamable = matches!(req.method, Method::Post | Method::Put)
+        && !req.is_chunked()
+        && req.header_str("Content-Encoding").is_none()
+        && req.path.contains(FILES_PATH)
+        && host_acceptable(req)
+        && redirect_for(&req.path, config).is_none()
+        && is_authorized(req, config);
+    if !streamable {
+        return None;
+    }
+    let dir_name = site_dir(req, config)?;
+    let file_path = file_path_in(&dir_name, &extract_path_filename(&req.path)?)?;
+    if !write_preconditions_hold(req, &file_path, config).await {
+        return None;
+    }
+    let append = req.method == Method::Post
+        && req
+            .query
+            .get(APPEND_QUERY)
+            .is_some_and(|append| append == "true");
+    Some(Upload::new(file_path, append))
+}
+
+// Copies the `len` byte body from `buffer` (starting at `body_start`) and then the
+// stream into the upload, one read at a time. Afterwards `buffer` holds the head and
+// any pipelined bytes, the body is gone. A failing write still consumes the body so
+// the connection stays usable.
+async fn stream_upload<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer: &mut Vec<u8>,
+    body_start: usize,
+    len: usize,
+    mut upload: Upload,
+    config: &Config,
+) -> Result<ResponseBuilder, ReadError> {
+    let mut failed = None;
+    let mut file = match upload.open().await {
+        Ok(file) => Some(file),
+        Err(e) => {
+            failed = Some(ServerError::from(e));
+            None
+        }
+    };
+    let buffered = (buffer.len() - body_start).min(len);
+    let mut chunk: Vec<u8> = buffer.drain(body_start..body_start + buffered).collect();
+    let mut left = len - buffered;
+    let mut temp_buf = vec![0; config.read_buffer_bytes];
+    loop {
+        if let Some(writer) = &mut file {
+            if let Err(e) = writer.write_all(&chunk).await {
+                failed = Some(ServerError::from(e));
+                file = None;
+            }
+        }
+        if left == 0 {
+            break;
+        }
+        let bytes_read = match read_chunk(stream, &mut temp_buf).await {
+            Ok(bytes_read) => bytes_read,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(ReadError::Rejected(HttpResponse::BadRequest))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let take = bytes_read.min(left);
+        chunk.clear();
+        chunk.extend_from_slice(&temp_buf[..take]);
+        buffer.extend_from_slice(&temp_buf[take..bytes_read]);
+        left -= take;
+    }
+    if let Some(e) = failed {
+        return Ok(HttpResponse::from(e).into());
+    }
+    let flushed = match &mut file {
+        Some(file) => file.flush().await,
+        None => Ok(()),
+    };
+    drop(file);
+    Ok(match flushed.and(upload.finish().await) {
+        Ok(()) => HttpResponse::Created.into(),
+        Err(e) => HttpResponse::from(ServerError::from(e)).into(),
+    })
+}
+
+// The `--index-file`, or else `index.html` of the served directory, for GET requests
+// that matched nothing while `--spa-fallback` is set
+async fn spa_fallback(req: &HttpRequest<'_>, config: &Config) -> Option<ResponseBuilder> {
+    if !config.spa_fallback || !matches!(req.method, Method::Get | Method::Head) {
+        return None;
+    }
+    let in_api = req
+        .path
+        .strip_prefix(config.spa_api_prefix.as_str())
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'));
+    if in_api {
+        return None;
+    }
+    let served = match &config.index_file {
+        Some(index_file) => serve_followed(req, None, Path::new(index_file), config).await,
+        None => {
+            let dir_name = site_dir(req, config)?;
+            let index = Path::new(&dir_name).join(INDEX_PAGE);
+            serve_followed(req, Some(&dir_name), &index, config).await
+        }
+    };
+    served.ok()
+}
+
+// Routes that reflect the request back, useful when testing but not meant to be
+// exposed in production
+fn is_debug_route(path: &str) -> bool {
+    path.starts_with(ECHO_PATH) || path == USER_AGENT_PATH
+}
+
+// Methods `handle_request` serves on `path`, None when no route matches it
+fn route_methods(path: &str) -> Option<&'static str> {
+    let read_only = "GET, HEAD, OPTIONS";
+    if path.is_empty()
+        || path.starts_with(ECHO_PATH)
+        || path == METRICS_PATH
+        || HEALTH_PATHS.contains(&path)
+        || path == USER_AGENT_PATH
+    {
+        Some(read_only)
+    } else if path.contains(FILES_PATH) {
+        Some("GET, HEAD, POST, PUT, PATCH, OPTIONS")
+    } else if path == UPLOAD_PATH {
+        Some("POST, OPTIONS")
+    } else {
+        None
+    }
+}
+
+// Target of the first `--redirect /from=/to` rule whose left side is the request path
+fn redirect_for(path: &str, config: &Config) -> Option<String> {
+    config
+        .redirects
+        .iter()
+        .find_map(|(from, to)| (from.strip_prefix('/').unwrap_or(from) == path).then(|| to.clone()))
+}
+
+// With `--absolute-redirects`, prefixes a target starting with `/` with the scheme and
+// host the request was made to. Without a host, e.g. from HTTP/1.0, it stays relative.
+fn absolute_location(req: &HttpRequest<'_>, location: String, config: &Config) -> String {
+    let relative = location.starts_with('/') && !location.starts_with("//");
+    match req.host() {
+        Some(host) if config.absolute_redirects && relative => {
+            let scheme = req
+                .scheme
+                .as_deref()
+                .unwrap_or(if config.tls_cert.is_some() {
+                    "https"
+                } else {
+                    "http"
+                });
+            format!("{}://{}{}", scheme, host, location)
+        }
+        _ => location,
+    }
+}
+
+// Joins a request path under the served directory, refusing anything that could
+// leave it such as `..` or an absolute path. An empty path is the directory itself.
+fn file_path_in(dir_name: &str, file_name: &str) -> Option<PathBuf> {
+    let relative = Path::new(file_name);
+    let contained = relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if !contained {
+        return None;
+    }
+    Some(Path::new(dir_name).join(relative))
+}
+
+// With `--no-follow-symlinks` nothing between the served directory and `path` may be
+// a symlink, and the resolved path must still lie inside the directory. A path that
+// doesn't exist passes, it is answered with a 404 later on.
+async fn free_of_symlinks(dir_name: &str, path: &Path) -> bool {
+    let dir = Path::new(dir_name);
+    let Ok(relative) = path.strip_prefix(dir) else {
+        return false;
+    };
+    let mut current = dir.to_path_buf();
+    for component in relative.components() {
+        current.push(component);
+        match fs::symlink_metadata(&current).await {
+            Ok(metadata) if metadata.file_type().is_symlink() => return false,
+            Ok(_) => {}
+            Err(_) => return true,
+        }
+    }
+    match (fs::canonicalize(dir).await, fs::canonicalize(path).await) {
+        (Ok(dir), Ok(path)) => path.starts_with(dir),
+        _ => false,
+    }
+}
+
+// Whether `--no-follow-symlinks` lets us serve `path`. Inside the served `dir_name` it
+// must be free of symlinks all the way down, the `--index-file` (no `dir_name`) may
+// lie anywhere so only the file itself is checked.
+async fn may_follow(dir_name: Option<&str>, path: &Path, config: &Config) -> bool {
+    if !config.no_follow_symlinks {
+        return true;
+    }
+    match dir_name {
+        Some(dir_name) => free_of_symlinks(dir_name, path).await,
+        None => !is_symlink(path).await,
+    }
+}
+
+async fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path)
+        .await
+        .is_ok_and(|metadata| metadata.file_type().is_symlink())
+}
+
+// Applies `--deny` and `--allow` to the last component of `path`
+fn name_permitted(path: &Path, config: &Config) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    let matches = |pattern: &String| glob_match(pattern, name);
+    !config.deny.iter().any(matches)
+        && (config.allow.is_empty() || config.allow.iter().any(matches))
+}
+
+// Whether the client sent `TE: trailers`, i.e. reads fields after the last chunk
+fn accepts_trailers(req: &HttpRequest) -> bool {
+    req.header_str("TE").is_some_and(|te| {
+        te.split(',')
+            .any(|coding| coding.trim().eq_ignore_ascii_case("trailers"))
+    })
+}
+
+// The `--cache-control` value for a served file, None when no pattern matches
+fn cache_control_for<'a>(path: &Path, config: &'a Config) -> Option<&'a str> {
+    let name = path.file_name()?.to_str()?;
+    config
+        .cache_control
+        .iter()
+        .find(|(pattern, _)| glob_match(pattern, name))
+        .map(|(_, value)| value.as_str())
+}
+
+// Matches `name` against a pattern where `*` stands for any run of characters and
+// `?` for exactly one
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was seen and how much of the name it had taken then
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // Let the star swallow one more character and retry
+                Some((star, taken)) => {
+                    p = star + 1;
+                    n = taken + 1;
+                    backtrack = Some((star, taken + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// An HTML page linking every entry of `dir`, which is served at `url_path`
+async fn directory_listing(dir: &Path, url_path: &str, config: &Config) -> io::Result<String> {
+    let mut names = Vec::new();
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let mut name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type().await?.is_dir() {
+            name.push('/');
+        } else if !name_permitted(&entry.path(), config) {
+            continue;
+        }
+        names.push(name);
+    }
+    names.sort();
+    let base = url_path.trim_end_matches('/');
+    let title = html_escape(&format!("/{}/", base));
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>Index of {0}</title></head>\n<body>\n<h1>Index of {0}</h1>\n<ul>\n",
+        title
+    );
+    for name in names {
+        page.push_str(&format!(
+            "<li><a href=\"/{}/{}\">{}</a></li>\n",
+            html_escape(&percent_encode(base)),
+            html_escape(&percent_encode(&name)),
+            html_escape(&name)
+        ));
+    }
+    page.push_str("</ul>\n</body>\n</html>\n");
+    Ok(page)
+}
+
+// Escapes everything but unreserved characters and `/` for use in a URL path
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+// Writes the file parts of a form upload into `--directory` under their submitted
+// names, text fields are accepted but not stored
+async fn save_uploads(req: &HttpRequest<'_>, config: &Config) -> HttpResponse {
+    let (Some(dir_name), Some(parts)) = (site_dir(req, config), req.multipart()) else {
+        return HttpResponse::BadRequest;
+    };
+    for part in &parts {
+        let Some(filename) = &part.filename else {
+            continue;
+        };
+        // Some clients send the whole client-side path, only its last segment is kept
+        let name = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+        // Browsers send an empty filename for a file input left blank
+        if name.is_empty() {
+            continue;
+        }
+        let Some(file_path) = file_path_in(&dir_name, name) else {
+            return HttpResponse::BadRequest;
+        };
+        if let Err(e) = write_file(&file_path, &part.body).await {
+            return e.into();
+        }
+    }
+    HttpResponse::Created
+}
+
+// Reads a file for a GET, preferring a precompressed `.gz` sibling when the client
+// accepts gzip. The response keeps the type of the original file either way.
+async fn serve_file(
+    req: &HttpRequest<'_>,
+    file_path: &Path,
+    config: &Config,
+) -> Result<ResponseBuilder, ServerError> {
+    serve_opened(req, file_path, open_file(file_path).await, config).await
+}
+
+// `serve_file` for a path `may_follow` allows, a 404 otherwise
+async fn serve_followed(
+    req: &HttpRequest<'_>,
+    dir_name: Option<&str>,
+    file_path: &Path,
+    config: &Config,
+) -> Result<ResponseBuilder, ServerError> {
+    if !may_follow(dir_name, file_path, config).await {
+        return Err(ServerError::NotFound);
+    }
+    serve_file(req, file_path, config).await
+}
+
+// `serve_file` for a file the caller already opened, or failed to
+async fn serve_opened(
+    req: &HttpRequest<'_>,
+    file_path: &Path,
+    opened: Result<(fs::File, Metadata), ServerError>,
+    config: &Config,
+) -> Result<ResponseBuilder, ServerError> {
+    let content_type = content_type_for(file_path);
+    let mut encoding = None;
+    let mut served = None;
+    for (coding, suffix) in PRECOMPRESSED {
+        if !accepts_encoding(req, coding) {
+            continue;
+        }
+        let mut sibling = file_path.as_os_str().to_owned();
+        sibling.push(suffix);
+        let sibling = PathBuf::from(sibling);
+        // A linked sibling is skipped in favour of the next one or the file itself
+        if config.no_follow_symlinks && is_symlink(&sibling).await {
+            continue;
+        }
+        if let Ok(compressed) = file_contents(&sibling, config).await {
+            encoding = Some(coding);
+            served = Some(compressed);
+            break;
+        }
+    }
+    let (contents, metadata) = match served {
+        Some(served) => served,
+        None => read_opened(file_path, opened?, config).await?,
+    };
+    // Rendered pages differ per request, so they carry no validators
+    let templated = encoding.is_none() && content_type == "text/html" && config.templating;
+    let contents = if templated {
+        let page = String::from_utf8_lossy(&contents);
+        render_template(&page, &template_vars(req, config)).into_bytes()
+    } else {
+        contents
+    };
+    // Validators describe the bytes actually sent, so the `.gz` sibling has its own
+    let modified = metadata.modified().ok().filter(|_| !templated);
+    let etag = modified.map(|modified| entity_tag(&contents, modified, !config.weak_etags));
+    // Whole identity bodies are compressed on the fly, ranges always address the
+    // stored bytes
+    let encoder = negotiate_encoder(req).filter(|_| {
+        encoding.is_none() && worth_compressing(req, content_type, contents.len(), config)
+    });
+    // An encoded variant is a different representation and needs a tag of its own
+    let full_etag = match (&etag, encoder) {
+        (Some(etag), Some(encoder)) => {
+            Some(format!("{}-{}\"", etag.trim_end_matches('"'), encoder.name))
+        }
+        (etag, _) => etag.clone(),
+    };
+    // A cached copy is revalidated before any range is considered
+    if let (Some(etag), Some(modified)) = (&full_etag, modified) {
+        if none_match(req, etag) {
+            let response = ResponseBuilder::new(HttpResponse::NotModified)
+                .header("ETag", etag.clone())
+                .header("Last-Modified", http_date(modified));
+            return Ok(match cache_control_for(file_path, config) {
+                Some(cache_control) => response.header("Cache-Control", cache_control),
+                None => response,
+            });
+        }
+    }
+    let response = match req.header_str("Range") {
+        Some(range) if if_range_matches(req, etag.as_deref(), modified) => {
+            range_response(contents, range)
+        }
+        _ => HttpResponse::OkStream(Some(contents)),
+    };
+    let compress = encoder.is_some() && matches!(&response, HttpResponse::OkStream(Some(_)));
+    let etag = if compress { full_etag } else { etag };
+    let mut response = ResponseBuilder::new(response).content_type(content_type);
+    if compress {
+        response = response.compress(req);
+    }
+    if let (Some(etag), Some(modified)) = (etag, modified) {
+        response = response
+            .header("ETag", etag)
+            .header("Last-Modified", http_date(modified));
+    }
+    if let Some(cache_control) = cache_control_for(file_path, config) {
+        response = response.header("Cache-Control", cache_control);
+    }
+    let whole = matches!(response.response, HttpResponse::OkStream(Some(_)));
+    if config.checksum_trailer && whole && accepts_trailers(req) {
+        response = response.trailer(CHECKSUM_TRAILER, crc32_checksum);
+    }
+    Ok(match encoding {
+        Some(encoding) => response.header("Content-Encoding", encoding),
+        None => response,
+    })
+}
+
+// Values for `{{NAME}}` placeholders, the built-in ones first so that
+// `--template-var name=value` can override them
+fn template_vars(req: &HttpRequest<'_>, config: &Config) -> HashMap<String, String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let (year, _, _) = civil_from_days((now / 86400) as i64);
+    let mut vars = HashMap::from([("YEAR".to_string(), year.to_string())]);
+    if let Some(host) = req.host() {
+        vars.insert("HOST".to_string(), html_escape(host));
+    }
+    vars.extend(config.template_vars.iter().cloned());
+    vars
+}
+
+// Replaces every `{{NAME}}` with its value, unknown names are left as written
+fn render_template(template: &str, vars: &HashMap<String, String>) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(open) = rest.find("{{") {
+        let Some(close) = rest[open + 2..].find("}}") else {
+            break;
+        };
+        let name = &rest[open + 2..open + 2 + close];
+        rendered.push_str(&rest[..open]);
+        match vars.get(name) {
+            Some(value) => rendered.push_str(value),
+            None => rendered.push_str(&rest[open..open + 4 + close]),
+        }
+        rest = &rest[open + 4 + close..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+// Identifies a version of a file. A strong tag hashes the contents and changes with
+// any byte, a weak one only reflects the size and modification time.
+fn entity_tag(contents: &[u8], modified: SystemTime, strong: bool) -> String {
+    if strong {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(contents);
+        return format!("\"{:016x}\"", hasher.finish());
+    }
+    let secs = modified
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    format!("W/\"{:x}-{:x}\"", contents.len(), secs)
+}
+
+// RFC 7232 section 2.3.2: strong comparison needs two identical strong tags, weak
+// comparison only the same opaque part
+fn strong_match(a: &str, b: &str) -> bool {
+    !a.starts_with("W/") && a == b
+}
+
+fn weak_match(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+// Whether a tag list header is `*` or lists a tag matching `etag` under `matches`
+fn tag_listed(tags: &str, etag: Option<&str>, matches: fn(&str, &str) -> bool) -> bool {
+    tags.split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || etag.is_some_and(|etag| matches(tag, etag)))
+}
+
+// `If-None-Match` on a read, which uses the weak comparison so that a cached copy
+// tagged either way is revalidated
+fn none_match(req: &HttpRequest, etag: &str) -> bool {
+    req.header_str("If-None-Match")
+        .is_some_and(|tags| tag_listed(tags, Some(etag), weak_match))
+}
+
+// Checks `If-Match` and `If-None-Match` against the file about to be written, so a
+// client only overwrites the version it last saw, or only creates a new file
+async fn write_preconditions_hold(
+    req: &HttpRequest<'_>,
+    file_path: &Path,
+    config: &Config,
+) -> bool {
+    if req.header_str("If-Match").is_none() && req.header_str("If-None-Match").is_none() {
+        return true;
+    }
+    // Anything but a readable regular file counts as no current file
+    let current = file_contents(file_path, config).await.ok();
+    let etag = current.as_ref().and_then(|(contents, metadata)| {
+        let modified = metadata.modified().ok()?;
+        Some(entity_tag(contents, modified, !config.weak_etags))
+    });
+    // Either header lists tags separated by commas, or is `*` for any current file.
+    // `If-Match` needs the strong comparison, `If-None-Match` the weak one.
+    let listed = |header: &str, matches: fn(&str, &str) -> bool| {
+        req.header_str(header)
+            .map(|tags| current.is_some() && tag_listed(tags, etag.as_deref(), matches))
+    };
+    listed("If-Match", strong_match).unwrap_or(true)
+        && !listed("If-None-Match", weak_match).unwrap_or(false)
+}
+
+// A range is only honoured when `If-Range` is absent or still names the current file,
+// either by its entity tag or by its exact modification date
+fn if_range_matches(req: &HttpRequest, etag: Option<&str>, modified: Option<SystemTime>) -> bool {
+    let Some(validator) = req.header_str("If-Range") else {
+        return true;
+    };
+    let validator = validator.trim();
+    if validator.starts_with('"') || validator.starts_with("W/") {
+        // Weak tags never match since the range must come from identical bytes
+        etag.is_some_and(|etag| strong_match(validator, etag))
+    } else {
+        let unix_secs =
+            |time: SystemTime| time.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs());
+        match (parse_http_date(validator), modified) {
+            (Some(date), Some(modified)) => unix_secs(date) == unix_secs(modified),
+            _ => false,
+        }
+    }
+}
+
+// `Content-Disposition` value asking the browser to save `path` under its own name,
+// with the name written as a quoted-string
+fn attachment(path: &Path) -> String {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let mut quoted = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c if c.is_control() => quoted.push('_'),
+            c => quoted.push(c),
+        }
+    }
+    format!("attachment; filename=\"{}\"", quoted)
+}
+
+// Whether `Accept-Encoding` admits `coding` with a non-zero quality, either by name
+// or through `*`. An entry naming the coding wins over the wildcard, so `*;q=0, gzip`
+// still admits gzip and `gzip;q=0, *` doesn't. Identity is admitted unless refused.
+fn accepts_encoding(req: &HttpRequest, coding: &str) -> bool {
+    let listed = encoding_listed(req, coding);
+    if coding.eq_ignore_ascii_case(IDENTITY) {
+        listed.unwrap_or(true)
+    } else {
+        listed.unwrap_or(false)
+    }
+}
+
+// What `Accept-Encoding` says about `coding`, None when it is not mentioned
+fn encoding_listed(req: &HttpRequest, coding: &str) -> Option<bool> {
+    encoding_quality(req, coding).map(|quality| quality > 0.0)
+}
+
+// The coding out of ENCODERS that the client rates highest, None when it accepts none
+fn negotiate_encoder(req: &HttpRequest) -> Option<&'static Encoder> {
+    let mut best: Option<(&Encoder, f32)> = None;
+    for encoder in &ENCODERS {
+        let quality = encoding_quality(req, encoder.name).unwrap_or(0.0);
+        if quality > best.map_or(0.0, |(_, best)| best) {
+            best = Some((encoder, quality));
+        }
+    }
+    best.map(|(encoder, _)| encoder)
+}
+
+// The quality `Accept-Encoding` gives `coding` by name or else through `*`
+fn encoding_quality(req: &HttpRequest, coding: &str) -> Option<f32> {
+    let accepted = req.header_str("Accept-Encoding")?;
+    let mut wildcard = None;
+    for entry in accepted.split(',') {
+        let mut params = entry.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let quality = params
+            .filter_map(|param| param.trim().split_once('='))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case("q"))
+            .map_or(1.0, |(_, q)| q.trim().parse::<f32>().unwrap_or(0.0));
+        if name.eq_ignore_ascii_case(coding) {
+            return Some(quality);
+        }
+        if name == "*" {
+            wildcard = Some(quality);
+        }
+    }
+    wildcard
+}
+
+// Whether compressing is likely to pay off, it doesn't for small bodies or media that is
+// compressed already. A client refusing identity gets an encoding regardless.
+fn worth_compressing(req: &HttpRequest, content_type: &str, len: usize, config: &Config) -> bool {
+    if !accepts_encoding(req, IDENTITY) {
+        return true;
+    }
+    let compressed = match content_type.split_once('/') {
+        Some(("image", subtype)) => subtype != "svg+xml",
+        Some(("audio" | "video", _)) => true,
+        _ => matches!(
+            content_type,
+            "application/zip" | "application/gzip" | "font/woff" | "font/woff2"
+        ),
+    };
+    len >= config.gzip_min_size && !compressed
+}
+
+// Media type of a served file, guessed from its extension
+fn content_type_for(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" | "mjs" => "text/javascript",
+        "json" => "application/json",
+        "txt" => "text/plain",
+        "csv" => "text/csv",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "mp4" => "video/mp4",
+        "mp3" => "audio/mpeg",
+        _ => OCTET_STREAM,
+    }
+}
+
+// Answers `Range: bytes=...` with the requested slices of `contents`
+fn range_response(contents: Vec<u8>, range: &str) -> HttpResponse {
+    // Other units and malformed sets are ignored, the whole file is sent instead
+    let spec = match range.split_once('=') {
+        Some((unit, spec)) if unit.trim().eq_ignore_ascii_case("bytes") => spec,
+        _ => return HttpResponse::OkStream(Some(contents)),
+    };
+    match parse_ranges(spec, contents.len()).map(coalesce_ranges) {
+        None => HttpResponse::OkStream(Some(contents)),
+        Some(ranges) if ranges.is_empty() => HttpResponse::RangeNotSatisfiable(contents.len()),
+        // RFC 7233 section 6.1: many small parts cost more than the file itself
+        Some(ranges) if ranges.len() > MAX_RANGES => HttpResponse::OkStream(Some(contents)),
+        Some(ranges) => HttpResponse::PartialContent { contents, ranges },
+    }
+}
+
+// Sorts ranges and merges those that overlap or touch, so the parts never add up to
+// more than the file however often a client repeats a range
+fn coalesce_ranges(mut ranges: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(usize, usize)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some((_, last_end)) if start <= last_end.saturating_add(1) => {
+                *last_end = (*last_end).max(end);
+            }
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+// Resolves a comma separated range set into inclusive (start, end) offsets, dropping
+// ranges that start past the end. None when the set is malformed, empty when nothing
+// is left.
+fn parse_ranges(spec: &str, total: usize) -> Option<Vec<(usize, usize)>> {
+    let mut ranges = Vec::new();
+    for range in spec.split(',') {
+        let (start, end) = range.trim().split_once('-')?;
+        let (start, end) = if start.is_empty() {
+            // A suffix range, the last `end` bytes
+            let suffix: usize = end.parse().ok()?;
+            if suffix == 0 {
+                continue;
+            }
+            (total.saturating_sub(suffix), total.checked_sub(1)?)
+        } else {
+            let start: usize = start.parse().ok()?;
+            let end = match end {
+                "" => total.saturating_sub(1),
+                end => {
+                    let end: usize = end.parse().ok()?;
+                    // A last position before the first is a syntax error
+                    if end < start {
+                        return None;
+                    }
+                    end.min(total.saturating_sub(1))
+                }
+            };
+            (start, end)
+        };
+        if start < total && start <= end {
+            ranges.push((start, end));
+        }
+    }
+    Some(ranges)
+}
+
+// Separator for multipart bodies, varied per response so it is unlikely to show up in the parts
+fn multipart_boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or(0);
+    format!("{:032x}", nanos)
+}
+
+fn health_report() -> String {
+    format!(
+        "status ok\nuptime_seconds {:.3}\nactive_connections {}\n",
+        STARTED_AT.elapsed().as_secs_f64(),
+        ACTIVE_CONNECTIONS.load(Ordering::SeqCst)
+    )
+}
+
+// Counters in `name value` lines, the way Prometheus scrapes plain text
+fn metrics_report() -> String {
+    let mut report = format!(
+        "requests_total {}\n",
+        REQUESTS_TOTAL.load(Ordering::Relaxed)
+    );
+    for (class, count) in RESPONSES_BY_CLASS.iter().enumerate() {
+        report.push_str(&format!(
+            "responses_{}xx {}\n",
+            class + 1,
+            count.load(Ordering::Relaxed)
+        ));
+    }
+    report.push_str(&format!(
+        "active_connections {}\nbytes_served {}\n",
+        ACTIVE_CONNECTIONS.load(Ordering::SeqCst),
+        BYTES_SERVED.load(Ordering::Relaxed)
+    ));
+    // Cumulative like a Prometheus histogram, each bucket counts everything below it
+    let mut count = 0;
+    for (i, bucket) in LATENCY_BUCKETS.iter().enumerate() {
+        count += bucket.load(Ordering::Relaxed);
+        let bound = LATENCY_BOUNDS
+            .get(i)
+            .map_or("+Inf".to_string(), |bound| bound.as_secs_f64().to_string());
+        report.push_str(&format!(
+            "request_duration_seconds_bucket{{le=\"{}\"}} {}\n",
+            bound, count
+        ));
+    }
+    report.push_str(&format!(
+        "request_duration_seconds_sum {}\nrequest_duration_seconds_count {}\n",
+        LATENCY_SUM_MICROS.load(Ordering::Relaxed) as f64 / 1e6,
+        count
+    ));
+    report
+}
+
+// Counts a handled request's time from parsed to written towards /metrics
+fn record_latency(elapsed: Duration) {
+    let bucket = LATENCY_BOUNDS
+        .iter()
+        .position(|&bound| elapsed <= bound)
+        .unwrap_or(LATENCY_BOUNDS.len());
+    LATENCY_BUCKETS[bucket].fetch_add(1, Ordering::Relaxed);
+    LATENCY_SUM_MICROS.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+}
+
+// Counts a written response towards /metrics
+fn record_response(status: u16, body_bytes: usize) {
+    REQUESTS_TOTAL.fetch_add(1, Ordering::Relaxed);
+    if let Some(count) = RESPONSES_BY_CLASS.get((status / 100).wrapping_sub(1) as usize) {
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+    BYTES_SERVED.fetch_add(body_bytes as u64, Ordering::Relaxed);
+}
+
+// Counts a request from `ip` against `--rate-limit` (requests per minute) and returns
+// the seconds to wait once the client is over it
+fn rate_limited(ip: IpAddr, config: &Config) -> Option<u64> {
+    let limit = config.rate_limit?;
+    let now = Instant::now();
+    let mut windows = RATE_LIMITS.lock().unwrap();
+    // Forget clients whose window is long over so the map doesn't grow forever
+    if windows.len() > RATE_LIMIT_PRUNE_AT {
+        windows.retain(|_, (_, started)| now.duration_since(*started) < RATE_LIMIT_WINDOW);
+    }
+    let (count, started) = windows.entry(ip).or_insert((0, now));
+    if now.duration_since(*started) >= RATE_LIMIT_WINDOW {
+        *count = 0;
+        *started = now;
+    }
+    *count += 1;
+    if *count <= limit {
+        return None;
+    }
+    let remaining = RATE_LIMIT_WINDOW.saturating_sub(now.duration_since(*started));
+    Some(remaining.as_secs().max(1))
+}
+
+// Checks the Basic credentials against `--auth user:pass`, open when unset
+fn is_authorized(req: &HttpRequest, config: &Config) -> bool {
+    let Some(expected) = &config.auth else {
+        return true;
+    };
+    let credentials = req
+        .header_str("Authorization")
+        .and_then(|value| value.trim().split_once(char::is_whitespace))
+        // Auth schemes are case-insensitive, RFC 7235 section 2.1
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Basic"))
+        .and_then(|(_, encoded)| BASE64.decode(encoded.trim()).ok());
+    credentials.is_some_and(|credentials| constant_time_eq(&credentials, expected.as_bytes()))
+}
+
+// Compares without short-circuiting so timing doesn't reveal the matching prefix
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// A successful body about to be sent without a content coding is replaced with a 406
+// when the client refused identity, e.g. with `br, identity;q=0` and no Brotli copy
+fn with_acceptable_encoding(response: ResponseBuilder, req: &HttpRequest) -> ResponseBuilder {
+    let has_body = match &response.response {
+        HttpResponse::Ok(Some(body)) => !body.is_empty(),
+        HttpResponse::OkStream(Some(body)) => !body.is_empty(),
+        HttpResponse::PartialContent { .. } => true,
+        _ => false,
+    };
+    let encoded = response.encoder.is_some()
+        || response
+            .headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("Content-Encoding"));
+    if has_body && !encoded && !accepts_encoding(req, IDENTITY) {
+        return HttpResponse::NotAcceptable.into();
+    }
+    response
+}
+
+// Adds the CORS headers when an allowed origin is configured
+fn with_cors(response: ResponseBuilder, req: &HttpRequest, config: &Config) -> ResponseBuilder {
+    let Some(origin) = &config.cors_origin else {
+        return response;
+    };
+    let response = response.header("Access-Control-Allow-Origin", origin.as_str());
+    if req.method != Method::Options {
+        return response;
+    }
+    let allow_headers = req
+        .header_str("Access-Control-Request-Headers")
+        .unwrap_or(CORS_DEFAULT_ALLOW_HEADERS);
+    response
+        .header(
+            "Access-Control-Allow-Methods",
+            route_methods(&req.path).unwrap_or(SERVER_METHODS),
+        )
+        .header("Access-Control-Allow-Headers", allow_headers)
+}
+
+// Tells the client whether the connection outlives this response
+// `remaining` is how many more requests the connection will take after this one
+fn with_connection(
+    response: ResponseBuilder,
+    req: &HttpRequest,
+    keep_alive: bool,
+    remaining: usize,
+    config: &Config,
+) -> ResponseBuilder {
+    if !keep_alive {
+        return response.header("Connection", "close");
+    }
+    let response = if req.version.closes_by_default() {
+        response.header("Connection", "keep-alive")
+    } else {
+        response
+    };
+    let keep_alive = format!(
+        "timeout={}, max={}",
+        config.keepalive_timeout.as_secs(),
+        remaining
+    );
+    response.header("Keep-Alive", keep_alive)
+}
+
+// Serves `404.html` from the served directory when there is one
+async fn not_found_page(req: &HttpRequest<'_>, config: &Config) -> HttpResponse {
+    let Some(dir_name) = site_dir(req, config) else {
+        return HttpResponse::NotFound(None);
+    };
+    let path = Path::new(&dir_name).join(NOT_FOUND_PAGE);
+    let page = if may_follow(Some(&dir_name), &path, config).await {
+        file_contents(&path, config)
+            .await
+            .ok()
+            .map(|(page, _)| page)
+    } else {
+        None
+    };
+    HttpResponse::NotFound(page)
+}
+// Formats a timestamp the way Common Log Format expects, e.g. 10/Oct/2000:13:55:36 +0000
+fn clf_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+// Formats an IMF-fixdate as used by HTTP headers, e.g. Sun, 06 Nov 1994 08:49:37 GMT
+fn http_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    let days = (secs / 86400) as i64;
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days + 4).rem_euclid(7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+// Parses an IMF-fixdate, the only HTTP-date format senders may generate
+fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let fields: Vec<&str> = date.split_ascii_whitespace().collect();
+    let [weekday, day, month, year, clock, "GMT"] = fields[..] else {
+        return None;
+    };
+    if !weekday.ends_with(',') {
+        return None;
+    }
+    let day: u32 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|name| *name == month)? as u32 + 1;
+    let year: i64 = year.parse().ok()?;
+    let mut clock = clock.split(':').map(|field| field.parse::<u64>().ok());
+    let (hours, minutes, seconds) = (clock.next()??, clock.next()??, clock.next()??);
+    if clock.next().is_some() || day == 0 || day > 31 || hours > 23 || minutes > 59 || seconds > 60
+    {
+        return None;
+    }
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    let secs = days * 86400 + hours * 3600 + minutes * 60 + seconds;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+// Converts a (year, month, day) triple into days since the unix epoch
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// Converts days since the unix epoch into a (year, month, day) triple
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+// Writes one Common Log Format line for a completed request to `sink`
+fn log_access<W: Write>(
+    sink: &mut W,
+    remote: Option<SocketAddr>,
+    req: Option<&HttpRequest>,
+    status: u16,
+    bytes: usize,
+    config: &Config,
+) {
+    if !config.access_log {
+        return;
+    }
+    let remote = req
+        .and_then(|req| forwarded_client(req, config))
+        .or_else(|| remote.map(|addr| addr.ip().to_string()))
+        .unwrap_or("-".to_string());
+    let request_line = req.map_or("-".to_string(), |req| {
+        format!("{} {} {}", req.method, req.target, req.version)
+    });
+    let bytes = if bytes == 0 {
+        "-".to_string()
+    } else {
+        bytes.to_string()
+    };
+    let _ = writeln!(
+        sink,
+        "{} - - [{}] \"{}\" {} {}",
+        remote,
+        clf_date(SystemTime::now()),
+        request_line,
+        status,
+        bytes
+    );
+}
+
+// The original client as reported by a proxy in front of the server, only believed
+// with `--trust-proxy` since any client can send these headers
+fn forwarded_client(req: &HttpRequest, config: &Config) -> Option<String> {
+    if !config.trust_proxy {
+        return None;
+    }
+    let client = match req.header_str("X-Forwarded-For") {
+        Some(forwarded_for) => forwarded_for.split(',').next()?.trim(),
+        None => req
+            .header_str("Forwarded")?
+            .split(',')
+            .next()?
+            .split(';')
+            .find_map(|pair| {
+                let (name, value) = pair.trim().split_once('=')?;
+                name.eq_ignore_ascii_case("for").then_some(value)
+            })?
+            .trim_matches('"'),
+    };
+    (!client.is_empty()).then(|| client.to_string())
+}
+
+// Size of the response body, i.e. everything after the header block
+fn body_len(response: &[u8]) -> usize {
+    response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map_or(0, |pos| response.len() - pos - 4)
+}
+
+// A client hanging up or an idle connection closed for shutdown is routine,
+// anything else is worth a line
+fn report_io_error(e: io::Error) {
+    if !matches!(
+        e.kind(),
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    ) {
+        log_error!("error: {}", e);
+    }
+}
+
+// Answers a request that was refused before it could be served. Once its head was
+// parsed the reply uses the client's version, before that it is HTTP/1.1.
+async fn reject<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    remote: Option<SocketAddr>,
+    req: Option<&HttpRequest<'_>>,
+    version: HttpVersion,
+    response: HttpResponse,
+    config: &Config,
+) {
+    let (status, _) = response.status();
+    // The connection is dropped afterwards since the stream position is unknown
+    let response = ResponseBuilder::new(response)
+        .header("Connection", "close")
+        .into_response(&version, config);
+    let bytes = body_len(&response);
+    log_access(&mut io::stdout(), remote, req, status, bytes, config);
+    record_response(status, body_len(&response));
+    let _ = stream.write_all(&response).await;
+    let _ = stream.flush().await;
+}
+
+// Serves requests off a plain or TLS stream until either side closes it
+async fn handle_connection<S>(
+    mut stream: S,
+    remote: Option<SocketAddr>,
+    config: &Config,
+    server: &ServerState,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let connection = ConnectionHandle::register(config.keepalive_timeout);
+    let reaped = connection.reaped.clone();
+    tokio::select! {
+        _ = serve_requests(&mut stream, remote, &connection, config, server) => {}
+        _ = reaped.notified() => log_debug!("Closing idle connection from {:?}", remote),
+    }
+    drain_and_close(&mut stream, config).await;
+}
+
+struct TrackedConnection {
+    // Set between requests, only then may the connection be reaped
+    idle_since: Option<Instant>,
+    // The keep-alive timeout of the configuration it is served with
+    timeout: Duration,
+    reaped: Arc<Notify>,
+}
+
+// Keeps a connection in CONNECTIONS for as long as it is served
+struct ConnectionHandle {
+    id: u64,
+    reaped: Arc<Notify>,
+}
+
+impl ConnectionHandle {
+    fn register(timeout: Duration) -> Self {
+        let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+        let reaped = Arc::new(Notify::new());
+        let tracked = TrackedConnection {
+            idle_since: Some(Instant::now()),
+            timeout,
+            reaped: reaped.clone(),
+        };
+        CONNECTIONS.lock().unwrap().insert(id, tracked);
+        ConnectionHandle { id, reaped }
+    }
+
+    fn set_idle(&self, idle: bool) {
+        if let Some(tracked) = CONNECTIONS.lock().unwrap().get_mut(&self.id) {
+            tracked.idle_since = idle.then(Instant::now);
+        }
+    }
+}
+
+impl Drop for ConnectionHandle {
+    fn drop(&mut self) {
+        CONNECTIONS.lock().unwrap().remove(&self.id);
+    }
+}
+
+// Closes connections idle for longer than the keep-alive timeout. The only timer for
+// it, a connection waiting on its next request is never timed out by the read itself.
+async fn reap_idle_connections(server: Arc<ServerState>) {
+    while !server.shutting_down() {
+        time::sleep(REAP_INTERVAL).await;
+        reap_expired(&CONNECTIONS.lock().unwrap(), Instant::now());
+    }
+}
+
+// Tells each connection idle past its timeout at `now` to close, returning how many
+fn reap_expired(connections: &HashMap<u64, TrackedConnection>, now: Instant) -> usize {
+    let mut reaped = 0;
+    for tracked in connections.values() {
+        if tracked
+            .idle_since
+            .is_some_and(|since| now.saturating_duration_since(since) >= tracked.timeout)
+        {
+            tracked.reaped.notify_one();
+            reaped += 1;
+        }
+    }
+    reaped
+}
+
+// Half-closes the stream and discards whatever the client already pipelined, so
+// closing with unread data doesn't reset the connection before the last response
+// has been received
+async fn drain_and_close<S>(stream: &mut S, config: &Config)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if stream.shutdown().await.is_err() {
+        return;
+    }
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    let mut discarded = 0;
+    let mut temp_buf = vec![0; config.read_buffer_bytes];
+    while discarded < DRAIN_MAX_BYTES {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match time::timeout(left, stream.read(&mut temp_buf)).await {
+            Ok(Ok(n)) if n > 0 => discarded += n,
+            _ => return,
+        }
+    }
+}
+
+async fn serve_requests<S>(
+    stream: &mut S,
+    remote: Option<SocketAddr>,
+    connection: &ConnectionHandle,
+    config: &Config,
+    server: &ServerState,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Bytes past the current request belong to the next pipelined one
+    let mut buf = Vec::new();
+    let mut remaining = config.keepalive_max;
+    loop {
+        connection.set_idle(true);
+        if let Err(e) = await_request(stream, &mut buf, config, server).await {
+            return report_io_error(e);
+        }
+        connection.set_idle(false);
+        let deadline = time::Instant::now() + config.request_timeout;
+        let body_pos = match before(deadline, process_stream(stream, &mut buf, config)).await {
+            Ok(body_pos) => body_pos,
+            Err(ReadError::Rejected(response)) => {
+                let version = sent_version(&buf);
+                return reject(stream, remote, None, version, response, config).await;
+            }
+            Err(ReadError::Io(e)) => return report_io_error(e),
+        };
+        let req_str = String::from_utf8_lossy(&buf[..body_pos]).into_owned();
+        let req = match HttpRequest::from_str(&req_str, config) {
+            Ok(req) => req,
+            Err(response) => {
+                log_warn!("Server does not support the http request {req_str}");
+                let version = sent_version(&buf);
+                return reject(stream, remote, None, version, response, config).await;
+            }
+        };
+        if req.missing_length() {
+            return reject(
+                stream,
+                remote,
+                Some(&req),
+                req.version,
+                HttpResponse::LengthRequired,
+                config,
+            )
+            .await;
+        }
+        let too_large = |len: usize| config.max_body_bytes.is_some_and(|max| len > max);
+        if too_large(req.content_length()) {
+            return reject(
+                stream,
+                remote,
+                Some(&req),
+                req.version,
+                HttpResponse::PayloadTooLarge,
+                config,
+            )
+            .await;
+        }
+        // The body is consumed whatever the method, even when the handler ignores it,
+        // so that a pipelined request after it starts at the right offset
+        // A client waiting for `100 Continue` holds back the body, chunked or not, so
+        // the interim response goes out unless the body has already started arriving
+        let body_pending = if req.is_chunked() {
+            buf.len() == body_pos
+        } else {
+            buf.len() < body_pos + req.content_length()
+        };
+        if body_pending && req.expects_continue() {
+            let interim = HttpResponse::Continue.into_response(&req.version, config);
+            if stream.write_all(&interim).await.is_err() {
+                return;
+            }
+        }
+        let retry_after = remote.and_then(|remote| rate_limited(remote.ip(), config));
+        // Uploads to `/files` go straight to disk instead of through `buf`
+        let upload = match retry_after {
+            Some(_) => None,
+            None => upload_target(&req, config).await,
+        };
+        let mut streamed = None;
+        let body_end = if let Some(upload) = upload {
+            let len = req.content_length();
+            match before(
+                deadline,
+                stream_upload(stream, &mut buf, body_pos, len, upload, config),
+            )
+            .await
+            {
+                Ok(response) => {
+                    streamed = Some(response);
+                    body_pos
+                }
+                Err(ReadError::Rejected(response)) => {
+                    return reject(stream, remote, Some(&req), req.version, response, config).await;
+                }
+                Err(ReadError::Io(e)) => return report_io_error(e),
+            }
+        } else if req.is_chunked() {
+            match before(
+                deadline,
+                read_chunked_body(stream, &mut buf, body_pos, config),
+            )
+            .await
+            {
+                Ok(body_end) => body_end,
+                Err(ReadError::Rejected(response)) => {
+                    return reject(stream, remote, Some(&req), req.version, response, config).await;
+                }
+                Err(ReadError::Io(e)) => return report_io_error(e),
+            }
+        } else {
+            let body_end = body_pos + req.content_length();
+            match before(deadline, read_body(stream, &mut buf, body_end, config)).await {
+                Ok(()) => body_end,
+                Err(ReadError::Rejected(response)) => {
+                    return reject(stream, remote, Some(&req), req.version, response, config).await;
+                }
+                Err(ReadError::Io(e)) => return report_io_error(e),
+            }
+        };
+        remaining = remaining.saturating_sub(1);
+        let keep_alive = req.keep_alive() && remaining > 0 && !server.shutting_down();
+        let decoded;
+        let body = match decode_body(&req, &buf[body_pos..body_end], config.max_body_bytes) {
+            // The limit covers what the handlers get to see, i.e. the decompressed body
+            Ok(Some(body)) if too_large(body.len()) => {
+                return reject(
+                    stream,
+                    remote,
+                    Some(&req),
+                    req.version,
+                    HttpResponse::PayloadTooLarge,
+                    config,
+                )
+                .await;
+            }
+            Ok(Some(body)) => {
+                decoded = body;
+                &decoded[..]
+            }
+            Ok(None) => &buf[body_pos..body_end],
+            Err(_) => {
+                return reject(
+                    stream,
+                    remote,
+                    Some(&req),
+                    req.version,
+                    HttpResponse::BadRequest,
+                    config,
+                )
+                .await
+            }
+        };
+        let req = req.with_body(body);
+        let handling_started = Instant::now();
+        let response = match (retry_after, streamed) {
+            (Some(retry_after), _) => HttpResponse::TooManyRequests(retry_after).into(),
+            (None, Some(response)) => response,
+            // A panicking handler fails only its own request, the body has been read
+            // so the connection can carry on
+            (None, None) => AssertUnwindSafe(handle_request(&req, config))
+                .catch_unwind()
+                .await
+                .unwrap_or_else(|_| HttpResponse::InternalServerError.into()),
+        };
+        let response = with_acceptable_encoding(response, &req);
+        let response = with_cors(response, &req, config);
+        let response = with_connection(response, &req, keep_alive, remaining, config);
+        let (status, _) = response.status();
+        let mut response = response.into_response(&req.version, config);
+        if req.method == Method::Head {
+            // The response was computed exactly as for a GET, so ranges, validators
+            // and compression give the same status and headers, Content-Length
+            // included. Only the body is left out.
+            response.truncate(response.len() - body_len(&response));
+        }
+        log_access(
+            &mut io::stdout(),
+            remote,
+            Some(&req),
+            status,
+            body_len(&response),
+            config,
+        );
+        record_response(status, body_len(&response));
+        let written = match stream.write_all(&response).await {
+            Ok(()) => stream.flush().await,
+            Err(e) => Err(e),
+        };
+        record_latency(handling_started.elapsed());
+        if written.is_err() || !keep_alive {
+            return;
+        }
+        buf.drain(..body_end);
+    }
+}
+
+// Waits for a free worker, or refuses the connection when the queue of waiting ones
+// is already full. The permit is held for as long as the connection is served.
+async fn admit(
+    pool: Option<Arc<Semaphore>>,
+    config: &Config,
+    server: &ServerState,
+) -> Result<Option<OwnedSemaphorePermit>, HttpResponse> {
+    let Some(pool) = pool else {
+        return Ok(None);
+    };
+    if let Ok(permit) = pool.clone().try_acquire_owned() {
+        return Ok(Some(permit));
+    }
+    if server.queued.fetch_add(1, Ordering::SeqCst) >= config.queue_capacity {
+        server.queued.fetch_sub(1, Ordering::SeqCst);
+        return Err(HttpResponse::ServiceUnavailable(OVERLOAD_RETRY_AFTER_SECS));
+    }
+    let permit = pool.acquire_owned().await.ok();
+    server.queued.fetch_sub(1, Ordering::SeqCst);
+    Ok(permit)
+}
+
+// Serves an admitted connection, or answers a refused one before closing it
+async fn serve_or_reject<S>(
+    mut stream: S,
+    remote: Option<SocketAddr>,
+    refused: Option<HttpResponse>,
+    config: &Config,
+    server: &ServerState,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match refused {
+        Some(response) => {
+            let version = HttpVersion::HTTP_1_1;
+            reject(&mut stream, remote, None, version, response, config).await;
+            drain_and_close(&mut stream, config).await;
+        }
+        None => handle_connection(stream, remote, config, server).await,
+    }
+}
+
+// Accepts admin connections until the server shuts down
+async fn serve_admin(listener: TcpListener, server: Arc<ServerState>) {
+    while !server.shutting_down() {
+        if let Ok(Ok((stream, _))) = time::timeout(ACCEPT_POLL_INTERVAL, listener.accept()).await {
+            tokio::spawn(handle_admin(stream, current_config(), server.clone()));
+        }
+    }
+}
+
+// Takes any stream like the HTTP side does, so commands can be fed without a socket
+async fn handle_admin<S>(stream: S, config: Arc<Config>, server: Arc<ServerState>)
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = AsyncBufReader::new(reader).lines();
+    while let Ok(Ok(Some(line))) = time::timeout(config.keepalive_timeout, lines.next_line()).await
+    {
+        let reply = match line.trim().to_ascii_uppercase().as_str() {
+            "STATS" => metrics_report(),
+            "SHUTDOWN" => {
+                log_info!("Shutdown requested on the admin port");
+                server.shut_down();
+                "OK\n".to_string()
+            }
+            "" => continue,
+            _ => "ERR unknown command\n".to_string(),
+        };
+        if writer.write_all(reply.as_bytes()).await.is_err() || server.shutting_down() {
+            return;
+        }
+    }
+}
+
+// Builds the acceptor for `--tls-cert` and `--tls-key` (PEM files), None when the
+// server should speak plain HTTP
+fn tls_acceptor(config: &Config) -> io::Result<Option<TlsAcceptor>> {
+    let (Some(cert_path), Some(key_path)) = (&config.tls_cert, &config.tls_key) else {
+        return Ok(None);
+    };
+    let mut cert_reader = BufReader::new(std::fs::File::open(cert_path)?);
+    let certs = rustls_pemfile::certs(&mut cert_reader).collect::<Result<Vec<_>, _>>()?;
+    let mut key_reader = BufReader::new(std::fs::File::open(key_path)?);
+    let key = rustls_pemfile::private_key(&mut key_reader)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no private key found"))?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+// What `--check` verifies beyond parsing: the served directories can be listed and
+// the TLS certificate and key load. Returns a summary of what would be served.
+fn check_config(config: &Config) -> Result<String, String> {
+    let readable = |dir: &str| {
+        std::fs::read_dir(dir)
+            .map(drop)
+            .map_err(|e| format!("cannot read directory {}: {}", dir, e))
+    };
+    if let Some(dir) = &config.directory {
+        readable(dir)?;
+    }
+    for (_, dir) in &config.vhosts {
+        readable(dir)?;
+    }
+    if let Some(index_file) = &config.index_file {
+        std::fs::File::open(index_file)
+            .map_err(|e| format!("cannot read index file {}: {}", index_file, e))?;
+    }
+    if config.tls_cert.is_some() != config.tls_key.is_some() {
+        return Err(format!(
+            "{} and {} must be given together",
+            TLS_CERT_ARG, TLS_KEY_ARG
+        ));
+    }
+    let tls = tls_acceptor(config)
+        .map_err(|e| format!("cannot load the TLS certificate and key: {}", e))?;
+    let address = match &config.bind_unix {
+        Some(path) => path.clone(),
+        None => format!("{}:{}", config.bind, config.port),
+    };
+    Ok(format!(
+        "Configuration OK: {} on {}, directory {}, {} virtual host(s), {} redirect(s), {} extra header(s)",
+        if tls.is_some() { "HTTPS" } else { "HTTP" },
+        address,
+        config.directory.as_deref().unwrap_or("none"),
+        config.vhosts.len(),
+        config.redirects.len(),
+        config.default_headers.len(),
+    ))
+}
+
+#[tokio::main]
+async fn main() {
+    log_info!("Logs from program will appear here!");
+    lazy_static::initialize(&STARTED_AT);
+    if let Err(e) = Config::from_args() {
+        log_error!("error: {}", e);
+        std::process::exit(2);
+    }
+    let config = current_config();
+    if config.check {
+        match check_config(&config) {
+            Ok(summary) => {
+                println!("{}", summary);
+                std::process::exit(0);
+            }
+            Err(e) => {
+                log_error!("error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let server = Arc::new(ServerState::default());
+    let interrupted = server.clone();
+    ctrlc::set_handler(move || interrupted.shut_down())
+        .expect("failed to install the Ctrl-C handler");
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup());
+    let tls = tls_acceptor(&config).expect("failed to load the TLS certificate and key");
+    let listener = match &config.bind_unix {
+        #[cfg(unix)]
+        Some(path) => Listener::Unix(bind_unix(path).unwrap()),
+        _ => Listener::Tcp(bind_listener(&config).unwrap()),
+    };
+    if let Some(port) = config.admin_port {
+        let admin = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        tokio::spawn(serve_admin(admin, server.clone()));
+    }
+    run(listener, tls, current_config, server).await;
+    #[cfg(unix)]
+    if let Some(path) = &config.bind_unix {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+#[cfg(unix)]
+async fn reload_on_hangup() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = signal(SignalKind::hangup()).expect("failed to install the SIGHUP handler");
+    while hangups.recv().await.is_some() {
+        reload_config();
+    }
+}
+
+// Binds with SO_REUSEADDR, and SO_REUSEPORT under `--reuse-port`, so a restarted
+// server can take the address over straight away
+fn bind_listener(config: &Config) -> io::Result<TcpListener> {
+    let addr = std::net::ToSocketAddrs::to_socket_addrs(&(config.bind.as_str(), config.port))?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to bind"))?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    if config.reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    socket.bind(&addr.into())?;
+    // The kernel caps this at its own limit, e.g. net.core.somaxconn on Linux
+    socket.listen(i32::try_from(config.backlog).unwrap_or(i32::MAX))?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+// Binds the `--bind-unix` socket, replacing a socket left behind by an earlier run.
+// Anything else at the path is left alone and the bind fails.
+#[cfg(unix)]
+fn bind_unix(path: &str) -> io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
+
+// Where connections come from, a TCP port or with `--bind-unix` a Unix socket
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    // A Unix socket peer has no address to log or rate limit by
+    async fn accept(&self) -> io::Result<(Connection, Option<SocketAddr>)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, remote) = listener.accept().await?;
+                Ok((Connection::Tcp(stream), Some(remote)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Connection::Unix(stream), None))
+            }
+        }
+    }
+}
+
+// An accepted connection, served the same way whatever it came in on
+enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+// What a running server shares with its connections. Kept per server rather than in
+// statics, so tests can run servers side by side and stop each on its own.
+#[derive(Default)]
+struct ServerState {
+    shutdown: AtomicBool,
+    // Connections open on this server, checked against `--max-connections`
+    active: AtomicUsize,
+    // Connections waiting for a worker
+    queued: AtomicUsize,
+}
+
+impl ServerState {
+    fn shut_down(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+
+    fn shutting_down(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+}
+
+// Serves connections from `listener` until `server` shuts down, then waits for the
+// ones in flight. The listener may be bound to any port, including an ephemeral one.
+// `current` gives the configuration for each accepted connection, main passes
+// `current_config` so a SIGHUP reload applies to new connections. Worker settings come
+// from the configuration at startup.
+async fn run<F>(listener: Listener, tls: Option<TlsAcceptor>, current: F, server: Arc<ServerState>)
+where
+    F: Fn() -> Arc<Config> + Send + 'static,
+{
+    tokio::spawn(reap_idle_connections(server.clone()));
+    let pool = current()
+        .workers
+        .map(|workers| Arc::new(Semaphore::new(workers)));
+    let mut workers: Vec<JoinHandle<()>> = Vec::new();
+    while !server.shutting_down() {
+        // Accepting with a timeout lets the loop observe the shutdown flag
+        match time::timeout(ACCEPT_POLL_INTERVAL, listener.accept()).await {
+            Ok(Ok((stream, remote))) => {
+                // Stage 6 (Multi connection server)
+                workers.retain(|worker| !worker.is_finished());
+                let config = current();
+                ACTIVE_CONNECTIONS.fetch_add(1, Ordering::SeqCst);
+                let active = server.active.fetch_add(1, Ordering::SeqCst);
+                let over_limit = config.max_connections.is_some_and(|max| active >= max);
+                let tls = tls.clone();
+                let pool = pool.clone();
+                let server = server.clone();
+                workers.push(tokio::spawn(async move {
+                    // Stage 1
+                    log_debug!("Accepted new connection");
+                    let admitted = if over_limit {
+                        Err(HttpResponse::ServiceUnavailable(OVERLOAD_RETRY_AFTER_SECS))
+                    } else {
+                        admit(pool, &config, &server).await
+                    };
+                    let (_permit, refused) = match admitted {
+                        Ok(permit) => (permit, None),
+                        Err(response) => (None, Some(response)),
+                    };
+                    match tls {
+                        Some(tls) => {
+                            // A client that never finishes the handshake is dropped
+                            // like an idle one
+                            let handshake =
+                                time::timeout(config.keepalive_timeout, tls.accept(stream));
+                            if let Ok(Ok(stream)) = handshake.await {
+                                serve_or_reject(stream, remote, refused, &config, &server).await;
+                            }
+                        }
+                        None => serve_or_reject(stream, remote, refused, &config, &server).await,
+                    }
+                    server.active.fetch_sub(1, Ordering::SeqCst);
+                    ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+                }));
+            }
+            Ok(Err(e)) => {
+                log_error!("error: {}", e);
+            }
+            Err(_elapsed) => {}
+        }
+    }
+    log_info!("Shutting down, draining {} connection(s)", workers.len());
+    for worker in workers {
+        let _ = worker.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    // Parses a request head, the trailing blank line is added here
+    fn request(head: &str) -> HttpRequest<'static> {
+        HttpRequest::from_str(&format!("{}\r\n\r\n", head), &Config::default()).unwrap()
+    }
+
+    // The header lines of a serialized response with `name`
+    fn header_lines<'a>(response: &'a str, name: &str) -> Vec<&'a str> {
+        response
+            .split("\r\n\r\n")
+            .next()
+            .unwrap_or_default()
+            .split("\r\n")
+            .filter_map(|line| line.split_once(": "))
+            .filter(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    // Runs `req` through the routing and serializes the response
+    async fn handled(req: &HttpRequest<'_>, config: &Config) -> String {
+        let response = handle_request(req, config).await;
+        String::from_utf8(response.into_response(&req.version, config)).unwrap()
+    }
+
+    // A directory of its own for each test, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            static NEXT: AtomicU64 = AtomicU64::new(0);
+            let name = format!(
+                "http-server-test-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            );
+            let dir = std::env::temp_dir().join(name);
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn path(&self) -> String {
+            self.0.to_string_lossy().into_owned()
+        }
+
+        fn write(&self, name: &str, contents: impl AsRef<[u8]>) {
+            let path = self.0.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // The defaults, serving `dir` under /files
+    fn serving(dir: &TempDir) -> Config {
+        Config {
+            directory: Some(dir.path()),
+            ..Config::default()
+        }
+    }
+
+    // Stops a server started by `spawn_test_server`
+    struct ShutdownHandle {
+        server: Arc<ServerState>,
+        running: JoinHandle<()>,
+    }
+
+    impl ShutdownHandle {
+        // Returns once the connections in flight have been served
+        async fn stop(self) {
+            self.server.shut_down();
+            self.running.await.unwrap();
+        }
+    }
+
+    // Runs a server with `config` on an ephemeral loopback port, over TLS when it names
+    // a certificate and key. The access log is off since it writes to stdout directly,
+    // past the test harness's capture.
+    fn spawn_test_server(config: Config) -> (SocketAddr, ShutdownHandle) {
+        let config = Arc::new(Config {
+            bind: "127.0.0.1".to_string(),
+            port: 0,
+            access_log: false,
+            ..config
+        });
+        let tls = tls_acceptor(&config).unwrap();
+        let listener = bind_listener(&config).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Arc::new(ServerState::default());
+        let running = tokio::spawn(run(
+            Listener::Tcp(listener),
+            tls,
+            move || config.clone(),
+            server.clone(),
+        ));
+        (addr, ShutdownHandle { server, running })
+    }
+
+    // How long a test client waits on the server before failing the test
+    const TEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+    struct TestResponse {
+        version: String,
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+        // Fields sent after the last chunk
+        trailers: Vec<(String, String)>,
+    }
+
+    impl TestResponse {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(header, _)| header.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        }
+
+        fn text(&self) -> String {
+            String::from_utf8_lossy(&self.body).into_owned()
+        }
+    }
+
+    // A connection to a test server. Bytes read past one response are kept for the
+    // next, so pipelined responses can be read one at a time.
+    struct TestClient {
+        stream: TcpStream,
+        buf: Vec<u8>,
+    }
+
+    impl TestClient {
+        async fn connect(addr: SocketAddr) -> Self {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            TestClient {
+                stream,
+                buf: Vec::new(),
+            }
+        }
+
+        async fn send(&mut self, raw: impl AsRef<[u8]>) {
+            self.stream.write_all(raw.as_ref()).await.unwrap();
+        }
+
+        // Reads more into `buf`, false once the server closed the connection
+        async fn fill(&mut self) -> bool {
+            let mut temp_buf = [0; 4096];
+            let read = time::timeout(TEST_TIMEOUT, self.stream.read(&mut temp_buf));
+            match read.await.expect("the server did not answer in time") {
+                Ok(0) | Err(_) => false,
+                Ok(n) => {
+                    self.buf.extend_from_slice(&temp_buf[..n]);
+                    true
+                }
+            }
+        }
+
+        async fn take(&mut self, len: usize) -> Vec<u8> {
+            while self.buf.len() < len {
+                assert!(self.fill().await, "the connection closed mid-response");
+            }
+            self.buf.drain(..len).collect()
+        }
+
+        async fn line(&mut self) -> String {
+            self.until(b"\r\n").await
+        }
+
+        // Reads up to and past `ending`, returning what came before it
+        async fn until(&mut self, ending: &[u8]) -> String {
+            loop {
+                let found = self.buf.windows(ending.len()).position(|w| w == ending);
+                if let Some(end) = found {
+                    let line = self.take(end + ending.len()).await;
+                    return String::from_utf8_lossy(&line[..end]).into_owned();
+                }
+                assert!(self.fill().await, "the connection closed mid-response");
+            }
+        }
+
+        // Reads the status line and headers of the next response
+        async fn head(&mut self) -> TestResponse {
+            let status_line = self.line().await;
+            let mut parts = status_line.split(' ');
+            let version = parts.next().unwrap_or_default().to_string();
+            let status = parts
+                .next()
+                .and_then(|status| status.parse().ok())
+                .unwrap_or_else(|| panic!("bad status line {:?}", status_line));
+            let mut headers = Vec::new();
+            loop {
+                let line = self.line().await;
+                if line.is_empty() {
+                    break;
+                }
+                let (name, value) = line.split_once(": ").expect("a header line");
+                headers.push((name.to_string(), value.to_string()));
+            }
+            TestResponse {
+                version,
+                status,
+                headers,
+                body: Vec::new(),
+                trailers: Vec::new(),
+            }
+        }
+
+        // Reads the next response with its body, framed like the server framed it
+        async fn response(&mut self) -> TestResponse {
+            let mut response = self.head().await;
+            if response.status < 200 || response.status == 204 || response.status == 304 {
+                return response;
+            }
+            if response.header("transfer-encoding") == Some("chunked") {
+                loop {
+                    let size_line = self.line().await;
+                    let size = usize::from_str_radix(size_line.trim(), 16).unwrap();
+                    if size == 0 {
+                        loop {
+                            let line = self.line().await;
+                            let Some((name, value)) = line.split_once(": ") else {
+                                break;
+                            };
+                            response
+                                .trailers
+                                .push((name.to_string(), value.to_string()));
+                        }
+                        break;
+                    }
+                    let chunk = self.take(size + 2).await;
+                    response.body.extend_from_slice(&chunk[..size]);
+                }
+            } else if let Some(len) = response.header("content-length") {
+                let len = len.parse().unwrap();
+                response.body = self.take(len).await;
+            } else {
+                while self.fill().await {}
+                response.body = std::mem::take(&mut self.buf);
+            }
+            response
+        }
+
+        // Whether the server closes the connection without sending anything more
+        async fn closed(&mut self) -> bool {
+            self.buf.is_empty() && !self.fill().await
+        }
+    }
+
+    // Sends `raw` on a new connection and reads the one response to it
+    async fn fetch(addr: SocketAddr, raw: impl AsRef<[u8]>) -> TestResponse {
+        let mut client = TestClient::connect(addr).await;
+        client.send(raw).await;
+        client.response().await
+    }
+
+    #[test]
+    fn overlapping_and_adjacent_ranges_are_merged() {
+        assert_eq!(
+            coalesce_ranges(vec![(20, 30), (0, 10), (5, 12), (13, 15), (40, 40)]),
+            [(0, 15), (20, 30), (40, 40)]
+        );
+        let spec = vec!["0-"; 2000].join(",");
+        let ranges = parse_ranges(&spec, 1 << 20).map(coalesce_ranges);
+        assert_eq!(ranges, Some(vec![(0, (1 << 20) - 1)]));
+    }
+
+    #[test]
+    fn too_many_ranges_get_the_whole_file() {
+        let contents = vec![b'x'; 100];
+        let spec: Vec<String> = (0..=MAX_RANGES)
+            .map(|i| format!("{0}-{0}", i * 2))
+            .collect();
+        let response = range_response(contents, &format!("bytes={}", spec.join(",")));
+        assert_eq!(response.status().0, 200);
+        let spec = &spec[..MAX_RANGES];
+        let response = range_response(vec![b'x'; 100], &format!("bytes={}", spec.join(",")));
+        assert_eq!(response.status().0, 206);
+    }
+
+    #[test]
+    fn header_names_are_case_insensitive() {
+        let req = request("POST /files/a HTTP/1.1\r\nhost: localhost\r\ncontent-LENGTH: 5");
+        assert_eq!(req.host(), Some("localhost"));
+        assert_eq!(req.header_u64("Content-Length"), Some(5));
+        assert_eq!(req.content_length(), 5);
+        assert!(!req.missing_length());
+        assert!(host_acceptable(&req));
+    }
+
+    #[test]
+    fn content_length_must_be_a_number() {
+        for length in ["abc", "-1", "1,2", "5, 5", "0x10", ""] {
+            let head = format!(
+                "POST /files/a HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+                length
+            );
+            let parsed = HttpRequest::from_str(&head, &Config::default()).map(|_| ());
+            assert_eq!(parsed.unwrap_err().status().0, 400, "{:?}", length);
+        }
+    }
+
+    #[test]
+    fn the_given_config_limits_the_target() {
+        let head = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(20));
+        let config = Config {
+            max_uri_bytes: 10,
+            ..Config::default()
+        };
+        assert!(matches!(
+            HttpRequest::from_str(&head, &config),
+            Err(HttpResponse::UriTooLong)
+        ));
+        assert!(HttpRequest::from_str(&head, &Config::default()).is_ok());
+    }
+
+    #[test]
+    fn any_host_port_is_accepted() {
+        for host in [
+            "localhost:8080",
+            "example.com:4221",
+            "[::1]:80",
+            "example.com",
+        ] {
+            let req = request(&format!("GET / HTTP/1.1\r\nHost: {}", host));
+            assert!(host_acceptable(&req), "{}", host);
+        }
+        let req = request("GET / HTTP/1.1\r\nHost: localhost:http");
+        assert!(!host_acceptable(&req));
+    }
+
+    #[test]
+    fn decompression_stops_past_the_body_limit() {
+        let bomb = gzip_body(&vec![0; 10 << 20]);
+        let req = request("POST /files/a HTTP/1.1\r\nContent-Encoding: gzip");
+        let decoded = decode_body(&req, &bomb, Some(1000)).unwrap().unwrap();
+        assert_eq!(decoded.len(), 1001);
+        let decoded = decode_body(&req, &gzip_body(b"hello"), Some(1000)).unwrap();
+        assert_eq!(decoded.as_deref(), Some(&b"hello"[..]));
+    }
+
+    #[test]
+    fn an_empty_encoded_body_is_not_decoded() {
+        let req = request("GET /echo/x HTTP/1.1\r\nContent-Encoding: gzip");
+        assert!(decode_body(&req, b"", None).unwrap().is_none());
+    }
+
+    #[test]
+    fn cookie_header_is_parsed_into_a_map() {
+        let req = request("GET / HTTP/1.1\r\nHost: localhost\r\nCookie: a=1; b= two ; junk");
+        assert_eq!(req.cookies.len(), 2);
+        assert_eq!(req.cookies["a"], "1");
+        assert_eq!(req.cookies["b"], "two");
+        assert!(!req.cookies.contains_key("junk"));
+    }
+
+    #[test]
+    fn each_cookie_gets_its_own_set_cookie_line() {
+        let session = Cookie::new("session", "abc 123")
+            .unwrap()
+            .path("/")
+            .max_age(3600)
+            .http_only();
+        let theme = Cookie::new("theme", "dark").unwrap().secure();
+        let response = ResponseBuilder::new(HttpResponse::NoContent)
+            .cookie(session)
+            .cookie(theme)
+            .into_response(&HttpVersion::HTTP_1_1, &Config::default());
+        let response = String::from_utf8(response).unwrap();
+        assert_eq!(
+            header_lines(&response, "Set-Cookie"),
+            [
+                "session=abc%20123; Path=/; Max-Age=3600; HttpOnly",
+                "theme=dark; Secure"
+            ]
+        );
+    }
+
+    #[test]
+    fn cookie_names_must_be_tokens() {
+        for name in ["", "a;b", "a=b", "a b", "a\r\nSet-Cookie: b", "\"a\""] {
+            assert!(Cookie::new(name, "1").is_none(), "{:?}", name);
+        }
+        assert!(Cookie::new("__Host-id_1", "1").is_some());
+    }
+
+    #[test]
+    fn cookie_paths_cannot_end_the_attribute() {
+        let cookie = Cookie::new("a", "1").unwrap().path("/x;\r\nSecure");
+        assert_eq!(cookie.to_string(), "a=1; Path=/xSecure");
+    }
+
+    #[tokio::test]
+    async fn the_root_answers_ok() {
+        let (addr, server) = spawn_test_server(Config::default());
+        let mut client = TestClient::connect(addr).await;
+        client
+            .send("GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await;
+        assert_eq!(client.response().await.status, 200);
+        client
+            .send("GET /echo/again HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await;
+        assert_eq!(client.response().await.text(), "again");
+        assert!(client.closed().await);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn stopping_one_server_leaves_the_others_running() {
+        let (first_addr, first) = spawn_test_server(Config::default());
+        let (second_addr, second) = spawn_test_server(Config::default());
+        first.stop().await;
+        assert!(TcpStream::connect(first_addr).await.is_err());
+        let response = fetch(second_addr, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(response.status, 200);
+        second.stop().await;
+    }
+
+    #[tokio::test]
+    async fn a_served_file_is_as_long_as_it_says() {
+        let dir = TempDir::new();
+        dir.write("data.bin", vec![7; 10_000]);
+        dir.write("sub/inner.txt", "inner");
+        let (addr, server) = spawn_test_server(Config {
+            list_directories: true,
+            ..serving(&dir)
+        });
+        let response = fetch(
+            addr,
+            "GET /files/data.bin HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("content-length"), Some("10000"));
+        assert_eq!(response.body, vec![7; 10_000]);
+        let response = fetch(addr, "GET /files/sub HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(response.status, 200);
+        assert!(response.text().contains("inner.txt"), "{}", response.text());
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn only_new_files_pass_if_none_match_any() {
+        let dir = TempDir::new();
+        dir.write("taken.txt", "old");
+        let (addr, server) = spawn_test_server(serving(&dir));
+        let put = |name: &str| {
+            format!(
+                "PUT /files/{} HTTP/1.1\r\nHost: localhost\r\nIf-None-Match: *\r\nContent-Length: 3\r\n\r\nnew",
+                name
+            )
+        };
+        assert_eq!(fetch(addr, put("taken.txt")).await.status, 412);
+        assert_eq!(fetch(addr, put("fresh.txt")).await.status, 201);
+        assert_eq!(std::fs::read(dir.0.join("fresh.txt")).unwrap(), b"new");
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn echo_returns_the_rest_of_the_path() {
+        let req = request("GET /echo/abc HTTP/1.1\r\nHost: localhost");
+        let response = handled(&req, &Config::default()).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert_eq!(
+            header_lines(&response, "Content-Type"),
+            ["text/plain; charset=utf-8"]
+        );
+        assert!(response.ends_with("\r\n\r\nabc"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn user_agent_returns_the_header() {
+        let req = request("GET /user-agent HTTP/1.1\r\nHost: localhost\r\nUser-Agent: curl/8.0");
+        let response = handled(&req, &Config::default()).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\ncurl/8.0"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn files_are_written_then_read_back() {
+        let dir = TempDir::new();
+        let config = serving(&dir);
+        let req = request("POST /files/new.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5");
+        let response = handled(&req.with_body(b"hello"), &config).await;
+        assert!(
+            response.starts_with("HTTP/1.1 201 CREATED\r\n"),
+            "{}",
+            response
+        );
+        let req = request("GET /files/new.txt HTTP/1.1\r\nHost: localhost");
+        let response = handled(&req, &config).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert_eq!(
+            header_lines(&response, "Content-Type"),
+            ["text/plain; charset=utf-8"]
+        );
+        assert!(response.ends_with("\r\n\r\nhello"), "{}", response);
+        let req = request("GET /files/missing.txt HTTP/1.1\r\nHost: localhost");
+        let response = handled(&req, &config).await;
+        assert!(
+            response.starts_with("HTTP/1.1 404 NOT FOUND\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn a_request_is_served_over_an_in_memory_stream() {
+        let (mut client, mut stream) = tokio::io::duplex(64 * 1024);
+        client
+            .write_all(b"GET /echo/abc HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let config = Config::default();
+        let connection = ConnectionHandle::register(config.keepalive_timeout);
+        let server = ServerState::default();
+        serve_requests(&mut stream, None, &connection, &config, &server).await;
+        drop(stream);
+        let mut written = String::new();
+        client.read_to_string(&mut written).await.unwrap();
+        assert_eq!(
+            written,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: 3\r\nConnection: close\r\n\r\nabc"
+        );
+    }
+
+    #[test]
+    fn both_versions_parse() {
+        let req = request("GET /echo/a HTTP/1.0");
+        assert_eq!(req.version, HttpVersion { major: 1, minor: 0 });
+        let req = request("GET /echo/a HTTP/1.1\r\nHost: localhost");
+        assert_eq!(req.version, HttpVersion::HTTP_1_1);
+    }
+
+    #[tokio::test]
+    async fn the_response_has_the_request_version() {
+        let (addr, server) = spawn_test_server(Config::default());
+        let response = fetch(addr, "GET /echo/a HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(response.version, "HTTP/1.1");
+        let mut client = TestClient::connect(addr).await;
+        client.send("GET /echo/a HTTP/1.0\r\n\r\n").await;
+        let response = client.response().await;
+        assert_eq!(
+            (response.version.as_str(), response.status),
+            ("HTTP/1.0", 200)
+        );
+        // HTTP/1.0 closes after the response unless asked to keep it open
+        assert!(client.closed().await);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn other_versions_get_505_or_an_http_1_1_answer() {
+        let (addr, server) = spawn_test_server(Config::default());
+        let response = fetch(addr, "GET / HTTP/2.0\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(
+            (response.version.as_str(), response.status),
+            ("HTTP/1.1", 505)
+        );
+        let response = fetch(addr, "GET / HTTP/0.9\r\n\r\n").await;
+        assert_eq!(response.status, 505);
+        // A later minor version is still HTTP/1, answered in the highest one we speak
+        let response = fetch(addr, "GET /echo/a HTTP/1.9\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(
+            (response.version.as_str(), response.status),
+            ("HTTP/1.1", 200)
+        );
+        // Errors found while parsing still answer in the client's version
+        let mut client = TestClient::connect(addr).await;
+        client.send("FOO / HTTP/1.0\r\n\r\n").await;
+        let response = client.response().await;
+        assert_eq!(
+            (response.version.as_str(), response.status),
+            ("HTTP/1.0", 501)
+        );
+        assert_eq!(response.header("connection"), Some("close"));
+        assert!(client.closed().await);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn continue_is_sent_before_the_body_is_read() {
+        let dir = TempDir::new();
+        let (addr, server) = spawn_test_server(serving(&dir));
+        let mut client = TestClient::connect(addr).await;
+        client
+            .send("POST /files/upload.txt HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\nContent-Length: 4\r\n\r\n")
+            .await;
+        assert_eq!(client.response().await.status, 100);
+        client.send("body").await;
+        assert_eq!(client.response().await.status, 201);
+        assert_eq!(std::fs::read(dir.0.join("upload.txt")).unwrap(), b"body");
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn http_1_1_needs_a_host() {
+        let (addr, server) = spawn_test_server(Config::default());
+        assert_eq!(fetch(addr, "GET / HTTP/1.1\r\n\r\n").await.status, 400);
+        assert_eq!(fetch(addr, "GET / HTTP/1.0\r\n\r\n").await.status, 200);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn shutting_down_finishes_the_request_in_flight() {
+        let (addr, server) = spawn_test_server(Config::default());
+        let mut client = TestClient::connect(addr).await;
+        client
+            .send("POST /echo/a HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\nbo")
+            .await;
+        // Let the server start on the request before shutting it down
+        time::sleep(Duration::from_millis(100)).await;
+        let stopping = tokio::spawn(server.stop());
+        client.send("dy").await;
+        let response = client.response().await;
+        assert_eq!(response.header("connection"), Some("close"));
+        assert!(client.closed().await);
+        time::timeout(TEST_TIMEOUT, stopping)
+            .await
+            .expect("the server did not stop")
+            .unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[test]
+    fn access_lines_are_in_common_log_format() {
+        let req = request("GET /echo/abc?x=1 HTTP/1.1\r\nHost: localhost");
+        let remote = "10.0.0.7:51234".parse().ok();
+        let mut sink = Vec::new();
+        log_access(&mut sink, remote, Some(&req), 200, 3, &Config::default());
+        let line = String::from_utf8(sink).unwrap();
+        let (prefix, rest) = line.split_once(" [").unwrap();
+        assert_eq!(prefix, "10.0.0.7 - -");
+        let (date, rest) = rest.split_once("] ").unwrap();
+        assert!(date.ends_with(" +0000"), "{}", date);
+        assert_eq!(rest, "\"GET /echo/abc?x=1 HTTP/1.1\" 200 3\n");
+        // An empty body is logged as `-`, like a request that never parsed
+        let mut sink = Vec::new();
+        log_access(&mut sink, None, None, 400, 0, &Config::default());
+        let line = String::from_utf8(sink).unwrap();
+        assert!(line.starts_with("- - - ["), "{}", line);
+        assert!(line.ends_with("] \"-\" 400 -\n"), "{}", line);
+    }
+
+    #[test]
+    fn log_dates_are_day_month_year_time() {
+        let time = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!(clf_date(time), "06/Nov/1994:08:49:37 +0000");
+    }
+
+    #[tokio::test]
+    async fn a_404_page_in_the_directory_is_served() {
+        let dir = TempDir::new();
+        let (addr, server) = spawn_test_server(serving(&dir));
+        let missing = "GET /files/missing.txt HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let response = fetch(addr, missing).await;
+        assert_eq!((response.status, response.body.len()), (404, 0));
+        dir.write("404.html", "<h1>Gone</h1>\n");
+        let response = fetch(addr, missing).await;
+        assert_eq!(response.status, 404);
+        assert_eq!(
+            response.header("content-type"),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(response.text(), "<h1>Gone</h1>\n");
+        server.stop().await;
+    }
+
+    #[test]
+    fn methods_parse_from_their_names() {
+        for method in [
+            Method::Get,
+            Method::Post,
+            Method::Put,
+            Method::Delete,
+            Method::Head,
+            Method::Connect,
+            Method::Options,
+            Method::Trace,
+            Method::Patch,
+        ] {
+            assert_eq!(Method::parse(method.as_str()), Some(method));
+        }
+        assert_eq!(Method::parse("BREW"), None);
+        // Method names are case-sensitive
+        assert_eq!(Method::parse("get"), None);
+    }
+
+    #[tokio::test]
+    async fn an_unknown_method_is_not_implemented() {
+        let (addr, server) = spawn_test_server(Config::default());
+        let response = fetch(addr, "BREW / HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(response.status, 501);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn cors_headers_follow_the_allowed_origin() {
+        let (addr, server) = spawn_test_server(Config {
+            cors_origin: Some("https://app.example".to_string()),
+            ..Config::default()
+        });
+        let response = fetch(
+            addr,
+            "OPTIONS /echo/a HTTP/1.1\r\nHost: localhost\r\nOrigin: https://app.example\r\nAccess-Control-Request-Method: PUT\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.status, 204);
+        assert_eq!(
+            response.header("access-control-allow-origin"),
+            Some("https://app.example")
+        );
+        // The methods the route takes, so that uploads with PUT and PATCH pass too
+        assert_eq!(
+            response.header("access-control-allow-methods"),
+            Some("GET, HEAD, OPTIONS")
+        );
+        assert!(response.header("access-control-allow-headers").is_some());
+        for method in ["PUT", "PATCH"] {
+            let preflight = format!(
+                "OPTIONS /files/a.txt HTTP/1.1\r\nHost: localhost\r\nOrigin: https://app.example\r\nAccess-Control-Request-Method: {}\r\n\r\n",
+                method
+            );
+            let response = fetch(addr, preflight).await;
+            assert_eq!(response.status, 204);
+            let allowed = response.header("access-control-allow-methods").unwrap();
+            assert!(
+                allowed.split(", ").any(|allowed| allowed == method),
+                "{}",
+                allowed
+            );
+        }
+        let get = "GET /echo/a HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let response = fetch(addr, get).await;
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.header("access-control-allow-origin"),
+            Some("https://app.example")
+        );
+        assert_eq!(response.header("access-control-allow-methods"), None);
+        server.stop().await;
+        let (addr, server) = spawn_test_server(Config::default());
+        let response = fetch(addr, get).await;
+        assert_eq!(response.header("access-control-allow-origin"), None);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn files_need_the_configured_credentials() {
+        let dir = TempDir::new();
+        dir.write("secret.txt", "s3cret");
+        let (addr, server) = spawn_test_server(Config {
+            auth: Some("user:pass".to_string()),
+            ..serving(&dir)
+        });
+        let get = |authorization: &str| {
+            format!(
+                "GET /files/secret.txt HTTP/1.1\r\nHost: localhost\r\n{}\r\n",
+                authorization
+            )
+        };
+        let basic =
+            |credentials: &str| format!("Authorization: Basic {}\r\n", BASE64.encode(credentials));
+        let response = fetch(addr, get(&basic("user:pass"))).await;
+        assert_eq!((response.status, response.text().as_str()), (200, "s3cret"));
+        let put = format!(
+            "PUT /files/new.txt HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: 1\r\n\r\nx",
+            basic("user:pass")
+        );
+        assert_eq!(fetch(addr, put).await.status, 201);
+        // The scheme is matched in any case, with any whitespace after it
+        let encoded = BASE64.encode("user:pass");
+        for authorization in [
+            format!("Authorization: basic {}\r\n", encoded),
+            format!("Authorization: BASIC \t {}\r\n", encoded),
+        ] {
+            let response = fetch(addr, get(&authorization)).await;
+            assert_eq!(response.status, 200, "{}", authorization);
+        }
+        let bearer = format!("Authorization: Bearer {}\r\n", encoded);
+        for authorization in [
+            basic("user:wrong"),
+            basic("user:pass2"),
+            bearer,
+            String::new(),
+        ] {
+            let response = fetch(addr, get(&authorization)).await;
+            assert_eq!(response.status, 401, "{}", authorization);
+            assert_eq!(
+                response.header("www-authenticate"),
+                Some("Basic realm=\"files\"")
+            );
+        }
+        server.stop().await;
+    }
+
+    #[test]
+    fn credentials_compare_in_full() {
+        assert!(constant_time_eq(b"user:pass", b"user:pass"));
+        assert!(!constant_time_eq(b"user:pass", b"user:pasS"));
+        assert!(!constant_time_eq(b"user:pass", b"user:pas"));
+    }
+
+    #[tokio::test]
+    async fn an_oversized_header_block_gets_431() {
+        let (addr, server) = spawn_test_server(Config::default());
+        let padding = "X-Padding: ".to_string() + &"p".repeat(MAX_HEADER_BYTES) + "\r\n";
+        let raw = format!("GET / HTTP/1.1\r\nHost: localhost\r\n{}\r\n", padding);
+        assert_eq!(fetch(addr, raw).await.status, 431);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn the_end_of_the_headers_is_found_across_reads() {
+        let (addr, server) = spawn_test_server(Config::default());
+        let mut client = TestClient::connect(addr).await;
+        // Split inside the blank line so the boundary straddles two reads
+        client
+            .send("GET /echo/split HTTP/1.1\r\nHost: localhost\r\n\r")
+            .await;
+        time::sleep(Duration::from_millis(50)).await;
+        client.send("\n").await;
+        assert_eq!(client.response().await.text(), "split");
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_are_answered_in_order() {
+        let (addr, server) = spawn_test_server(Config::default());
+        let mut client = TestClient::connect(addr).await;
+        client
+            .send(
+                "GET /echo/one HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                 GET /echo/two HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                 GET /echo/three HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            )
+            .await;
+        for expected in ["one", "two", "three"] {
+            assert_eq!(client.response().await.text(), expected);
+        }
+        // A body arriving together with the next request's headers
+        client
+            .send(
+                "POST /echo/body HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello\
+                 GET /echo/after HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            )
+            .await;
+        client.response().await;
+        assert_eq!(client.response().await.text(), "after");
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn health_reports_the_uptime() {
+        let (addr, server) = spawn_test_server(Config::default());
+        for path in HEALTH_PATHS {
+            let raw = format!("GET /{} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+            let response = fetch(addr, raw).await;
+            assert_eq!(response.status, 200);
+            let text = response.text();
+            let uptime = text
+                .lines()
+                .find_map(|line| line.strip_prefix("uptime_seconds "))
+                .and_then(|uptime| uptime.parse::<f64>().ok());
+            assert!(uptime.is_some_and(|uptime| uptime >= 0.0), "{}", text);
+            assert!(text.contains("active_connections "), "{}", text);
+        }
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn two_ranges_come_back_as_multipart_byteranges() {
+        let dir = TempDir::new();
+        dir.write("alphabet.txt", "abcdefghijklmnopqrstuvwxyz");
+        let (addr, server) = spawn_test_server(serving(&dir));
+        let get = "GET /files/alphabet.txt HTTP/1.1\r\nHost: localhost\r\n";
+        let response = fetch(addr, format!("{}\r\n", get)).await;
+        assert_eq!(response.header("accept-ranges"), Some("bytes"));
+        let response = fetch(addr, format!("{}Range: bytes=0-2,10-12\r\n\r\n", get)).await;
+        assert_eq!(response.status, 206);
+        let content_type = response.header("content-type").unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let text = response.text();
+        let delimiter = format!("--{}", boundary);
+        let mut parts = text.split(delimiter.as_str());
+        assert_eq!(parts.next(), Some(""));
+        let mut ranges = Vec::new();
+        for part in parts.by_ref().take(2) {
+            let (head, body) = part.split_once("\r\n\r\n").unwrap();
+            let content_range = head
+                .split("\r\n")
+                .find_map(|line| line.strip_prefix("Content-Range: "))
+                .unwrap();
+            ranges.push((
+                content_range.to_string(),
+                body.trim_end_matches("\r\n").to_string(),
+            ));
+        }
+        assert_eq!(
+            ranges,
+            [
+                ("bytes 0-2/26".to_string(), "abc".to_string()),
+                ("bytes 10-12/26".to_string(), "klm".to_string()),
+            ]
+        );
+        // The closing delimiter ends the body
+        assert_eq!(parts.next(), Some("--\r\n"));
+        assert_eq!(parts.next(), None);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn a_gz_sibling_is_served_verbatim() {
+        let dir = TempDir::new();
+        let css = "body { color: red; }\n".repeat(20);
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(css.as_bytes()).unwrap();
+        let gzipped = encoder.finish().unwrap();
+        dir.write("style.css", &css);
+        dir.write("style.css.gz", &gzipped);
+        let (addr, server) = spawn_test_server(serving(&dir));
+        let get = "GET /files/style.css HTTP/1.1\r\nHost: localhost\r\n";
+        let response = fetch(addr, format!("{}Accept-Encoding: gzip\r\n\r\n", get)).await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("content-encoding"), Some("gzip"));
+        assert_eq!(
+            response.header("content-type"),
+            Some("text/css; charset=utf-8")
+        );
+        assert_eq!(response.body, gzipped);
+        let response = fetch(addr, format!("{}\r\n", get)).await;
+        assert_eq!(response.header("content-encoding"), None);
+        assert_eq!(response.text(), css);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn a_body_without_a_length_gets_411() {
+        let dir = TempDir::new();
+        let (addr, server) = spawn_test_server(serving(&dir));
+        let response = fetch(
+            addr,
+            "POST /files/unsized.txt HTTP/1.1\r\nHost: localhost\r\n\r\nsome data",
+        )
+        .await;
+        assert_eq!(response.status, 411);
+        assert!(!dir.0.join("unsized.txt").exists());
+        let response = fetch(
+            addr,
+            "POST /files/chunked.txt HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n4\r\ndata\r\n0\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.status, 201);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn an_overlong_target_gets_414() {
+        let (addr, server) = spawn_test_server(Config::default());
+        let long = "a".repeat(DEFAULT_MAX_URI_BYTES);
+        let raw = format!("GET /echo/{} HTTP/1.1\r\nHost: localhost\r\n\r\n", long);
+        assert_eq!(fetch(addr, raw).await.status, 414);
+        let raw = format!(
+            "GET /echo/{} HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            &long[..100]
+        );
+        assert_eq!(fetch(addr, raw).await.status, 200);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn appending_posts_concatenate() {
+        let dir = TempDir::new();
+        let (addr, server) = spawn_test_server(serving(&dir));
+        let post = |query: &str, body: &str| {
+            format!(
+                "POST /files/log.txt{} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+                query,
+                body.len(),
+                body
+            )
+        };
+        fetch(addr, post("?append=true", "first\n")).await;
+        fetch(addr, post("?append=true", "second\n")).await;
+        let log = dir.0.join("log.txt");
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "first\nsecond\n");
+        // Without the flag the file is replaced
+        assert_eq!(fetch(addr, post("", "fresh\n")).await.status, 201);
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "fresh\n");
+        server.stop().await;
+    }
+
+    #[test]
+    fn absolute_form_targets_split_into_their_parts() {
+        let absolute =
+            request("GET http://example.com:8080/echo/a%20b?x=1 HTTP/1.1\r\nHost: other");
+        let origin = request("GET /echo/a%20b?x=1 HTTP/1.1\r\nHost: example.com:8080");
+        assert_eq!(absolute.scheme.as_deref(), Some("http"));
+        assert_eq!(absolute.authority.as_deref(), Some("example.com:8080"));
+        assert_eq!(absolute.path, origin.path);
+        assert_eq!(absolute.query, origin.query);
+        // The authority in the target takes precedence over the Host header
+        assert_eq!(absolute.host(), Some("example.com:8080"));
+        assert_eq!(origin.scheme, None);
+        let root = request("GET http://example.com HTTP/1.1\r\nHost: example.com");
+        assert_eq!(
+            root.path,
+            request("GET / HTTP/1.1\r\nHost: example.com").path
+        );
+    }
+
+    #[test]
+    fn requests_past_the_limit_are_counted_per_address() {
+        let config = Config {
+            rate_limit: Some(2),
+            ..Config::default()
+        };
+        let (busy, other) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        assert_eq!(rate_limited(busy, &config), None);
+        assert_eq!(rate_limited(busy, &config), None);
+        let retry_after = rate_limited(busy, &config);
+        assert!(retry_after.is_some_and(|secs| (1..=60).contains(&secs)));
+        assert_eq!(rate_limited(other, &config), None);
+    }
+
+    #[tokio::test]
+    async fn a_client_over_the_rate_limit_gets_429() {
+        let (addr, server) = spawn_test_server(Config {
+            rate_limit: Some(3),
+            ..Config::default()
+        });
+        let get = "GET /echo/a HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let mut client = TestClient::connect(addr).await;
+        let mut responses = Vec::new();
+        for _ in 0..5 {
+            client.send(get).await;
+            responses.push(client.response().await);
+        }
+        assert_eq!(
+            responses
+                .iter()
+                .map(|response| response.status)
+                .collect::<Vec<_>>(),
+            [200, 200, 200, 429, 429]
+        );
+        assert!(responses[3].header("retry-after").is_some());
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn downloads_get_a_quoted_attachment_name() {
+        let dir = TempDir::new();
+        dir.write("my report.txt", "report");
+        let (addr, server) = spawn_test_server(serving(&dir));
+        let response = fetch(
+            addr,
+            "GET /files/my%20report.txt?download=true HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.header("content-disposition"),
+            Some("attachment; filename=\"my report.txt\"")
+        );
+        let response = fetch(
+            addr,
+            "GET /files/my%20report.txt HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.header("content-disposition"), None);
+        server.stop().await;
+    }
+
+    #[test]
+    fn attachment_names_escape_quotes() {
+        assert_eq!(
+            attachment(Path::new("/srv/say \"hi\"\\.txt")),
+            "attachment; filename=\"say \\\"hi\\\"\\\\.txt\""
+        );
+    }
+
+    const FORM_BODY: &str = "--XyZ\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\
+        \r\n\
+        Holiday\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"photo\"; filename=\"beach.txt\"\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        sand\r\nand sea\r\n\
+        --XyZ--\r\n";
+
+    #[test]
+    fn form_data_splits_into_its_fields() {
+        let req = request(&format!(
+            "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Type: multipart/form-data; boundary=XyZ\r\nContent-Length: {}",
+            FORM_BODY.len()
+        ));
+        let req = req.with_body(FORM_BODY.as_bytes());
+        let parts = req.multipart().unwrap();
+        let fields: Vec<_> = parts
+            .iter()
+            .map(|part| {
+                (
+                    part.name.as_str(),
+                    part.filename.as_deref(),
+                    part.content_type.as_deref(),
+                    String::from_utf8_lossy(&part.body),
+                )
+            })
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                ("title", None, None, "Holiday".into()),
+                (
+                    "photo",
+                    Some("beach.txt"),
+                    Some("text/plain"),
+                    "sand\r\nand sea".into()
+                ),
+            ]
+        );
+        // Without the closing delimiter the body is malformed
+        let truncated = &FORM_BODY[..FORM_BODY.len() - "--XyZ--\r\n".len()];
+        assert!(req.with_body(truncated.as_bytes()).multipart().is_none());
+    }
+
+    #[tokio::test]
+    async fn uploaded_file_parts_are_saved() {
+        let dir = TempDir::new();
+        let (addr, server) = spawn_test_server(serving(&dir));
+        let raw = format!(
+            "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Type: multipart/form-data; boundary=XyZ\r\nContent-Length: {}\r\n\r\n{}",
+            FORM_BODY.len(),
+            FORM_BODY
+        );
+        assert_eq!(fetch(addr, raw).await.status, 201);
+        let saved = std::fs::read_to_string(dir.0.join("beach.txt")).unwrap();
+        assert_eq!(saved, "sand\r\nand sea");
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn each_virtual_host_serves_its_own_directory() {
+        let (default, first, second) = (TempDir::new(), TempDir::new(), TempDir::new());
+        for (dir, site) in [
+            (&default, "default"),
+            (&first, "first"),
+            (&second, "second"),
+        ] {
+            dir.write("site.txt", site);
+        }
+        let (addr, server) = spawn_test_server(Config {
+            vhosts: vec![
+                ("first.test".to_string(), first.path()),
+                ("second.test".to_string(), second.path()),
+            ],
+            ..serving(&default)
+        });
+        for (host, site) in [
+            ("first.test", "first"),
+            ("SECOND.test:4221", "second"),
+            ("other.test", "default"),
+        ] {
+            let raw = format!("GET /files/site.txt HTTP/1.1\r\nHost: {}\r\n\r\n", host);
+            assert_eq!(fetch(addr, raw).await.text(), site, "{}", host);
+        }
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn trace_echoes_the_request_head() {
+        let (addr, server) = spawn_test_server(Config::default());
+        let head = "TRACE /echo/a HTTP/1.1\r\nHost: localhost\r\nX-Custom: kept as sent\r\nContent-Length: 4\r\n\r\n";
+        let response = fetch(addr, format!("{}body", head)).await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("content-type"), Some("message/http"));
+        assert_eq!(response.text(), head);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn the_connection_closes_after_the_maximum_requests() {
+        let (addr, server) = spawn_test_server(Config {
+            keepalive_max: 3,
+            ..Config::default()
+        });
+        let mut client = TestClient::connect(addr).await;
+        let get = "GET /echo/a HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        for max in [2, 1] {
+            client.send(get).await;
+            let response = client.response().await;
+            let keep_alive = format!(
+                "timeout={}, max={}",
+                DEFAULT_KEEPALIVE_TIMEOUT.as_secs(),
+                max
+            );
+            assert_eq!(response.header("keep-alive"), Some(keep_alive.as_str()));
+        }
+        client.send(get).await;
+        let response = client.response().await;
+        assert_eq!(response.header("connection"), Some("close"));
+        assert_eq!(response.header("keep-alive"), None);
+        assert!(client.closed().await);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn an_idle_connection_closes_after_the_timeout() {
+        let (addr, server) = spawn_test_server(Config {
+            keepalive_timeout: Duration::from_secs(1),
+            ..Config::default()
+        });
+        let mut client = TestClient::connect(addr).await;
+        client
+            .send("GET /echo/a HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await;
+        let response = client.response().await;
+        assert_eq!(response.header("keep-alive"), Some("timeout=1, max=99"));
+        let idle = Instant::now();
+        assert!(client.closed().await);
+        assert!(idle.elapsed() >= Duration::from_millis(900));
+        server.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn many_concurrent_requests_are_all_answered() {
+        let (addr, server) = spawn_test_server(Config::default());
+        let requests = (0..200).map(|i| async move {
+            let raw = format!("GET /echo/{} HTTP/1.1\r\nHost: localhost\r\n\r\n", i);
+            (i, fetch(addr, raw).await)
+        });
+        for (i, response) in futures::future::join_all(requests).await {
+            assert_eq!((response.status, response.text()), (200, i.to_string()));
+        }
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn a_tls_client_gets_the_root() {
+        use tokio_rustls::rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+        let dir = TempDir::new();
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        dir.write("cert.pem", certified.cert.pem());
+        dir.write("key.pem", certified.key_pair.serialize_pem());
+        let (addr, server) = spawn_test_server(Config {
+            tls_cert: Some(format!("{}/cert.pem", dir.path())),
+            tls_key: Some(format!("{}/key.pem", dir.path())),
+            ..Config::default()
+        });
+        let mut roots = RootCertStore::empty();
+        roots.add(certified.cert.der().clone()).unwrap();
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let name = ServerName::try_from("localhost").unwrap();
+        let mut stream = connector.connect(name, tcp).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        time::timeout(TEST_TIMEOUT, stream.read_to_end(&mut response))
+            .await
+            .expect("the server did not answer in time")
+            .unwrap();
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        server.stop().await;
+    }
+
+    #[test]
+    fn a_chunked_body_reassembles_to_the_content() {
+        let content: Vec<u8> = (0..RESPONSE_CHUNK_BYTES * 5 / 2).map(|i| i as u8).collect();
+        let mut framed = &chunk_body(&content, &[])[..];
+        let mut reassembled = Vec::new();
+        let mut sizes = Vec::new();
+        loop {
+            let line_end = framed.windows(2).position(|w| w == b"\r\n").unwrap();
+            let size_line = std::str::from_utf8(&framed[..line_end]).unwrap();
+            let size = usize::from_str_radix(size_line, 16).unwrap();
+            framed = &framed[line_end + 2..];
+            sizes.push(size);
+            if size == 0 {
+                break;
+            }
+            reassembled.extend_from_slice(&framed[..size]);
+            assert_eq!(&framed[size..size + 2], b"\r\n");
+            framed = &framed[size + 2..];
+        }
+        assert_eq!(framed, b"\r\n");
+        assert_eq!(
+            sizes,
+            [
+                RESPONSE_CHUNK_BYTES,
+                RESPONSE_CHUNK_BYTES,
+                RESPONSE_CHUNK_BYTES / 2,
+                0
+            ]
+        );
+        assert_eq!(reassembled, content);
+    }
+
+    #[tokio::test]
+    async fn a_directory_listing_is_sent_chunked() {
+        let dir = TempDir::new();
+        for i in 0..200 {
+            dir.write(&format!("sub/file-{:03}.txt", i), "");
+        }
+        let (addr, server) = spawn_test_server(Config {
+            list_directories: true,
+            ..serving(&dir)
+        });
+        let response = fetch(addr, "GET /files/sub HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(response.header("transfer-encoding"), Some("chunked"));
+        assert_eq!(response.header("content-length"), None);
+        let listing = directory_listing(&dir.0.join("sub"), "files/sub", &Config::default())
+            .await
+            .unwrap();
+        assert_eq!(response.text(), listing);
+        server.stop().await;
+    }
+
+    #[test]
+    fn paths_are_normalized() {
+        for (path, normalized) in [
+            ("/files//sub/./file.txt", Some("files/sub/file.txt")),
+            ("/echo/../echo/hi", Some("echo/hi")),
+            ("/files/sub/", Some("files/sub/")),
+            ("//files///", Some("files/")),
+            ("/a/b/../..", Some("")),
+            ("/", Some("")),
+            ("", Some("")),
+            ("/..", None),
+            ("..", None),
+            ("/a/../../b", None),
+            ("/.../x", Some(".../x")),
+        ] {
+            assert_eq!(normalize_path(path).as_deref(), normalized, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_are_routed_on_the_normalized_path() {
+        let (addr, server) = spawn_test_server(Config::default());
+        let response = fetch(
+            addr,
+            "GET /echo/../echo//hi HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        )
+        .await;
+        assert_eq!((response.status, response.text().as_str()), (200, "hi"));
+        let response = fetch(addr, "GET /../echo/hi HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(response.status, 400);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn if_range_only_honours_a_current_validator() {
+        let dir = TempDir::new();
+        dir.write("resume.bin", "0123456789");
+        let (addr, server) = spawn_test_server(serving(&dir));
+        let get = "GET /files/resume.bin HTTP/1.1\r\nHost: localhost\r\n";
+        let full = fetch(addr, format!("{}\r\n", get)).await;
+        let etag = full.header("etag").unwrap().to_string();
+        let modified = full.header("last-modified").unwrap().to_string();
+        let ranged =
+            |validator: &str| format!("{}Range: bytes=5-\r\nIf-Range: {}\r\n\r\n", get, validator);
+        for validator in [etag.as_str(), modified.as_str()] {
+            let response = fetch(addr, ranged(validator)).await;
+            assert_eq!((response.status, response.text().as_str()), (206, "56789"));
+        }
+        for stale in ["\"stale\"", "Sun, 06 Nov 1994 08:49:37 GMT"] {
+            let response = fetch(addr, ranged(stale)).await;
+            assert_eq!(
+                (response.status, response.text().as_str()),
+                (200, "0123456789")
+            );
+        }
+        server.stop().await;
+    }
+
+    #[test]
+    fn numeric_and_textual_headers_have_accessors() {
+        let req =
+            request("POST /files/big HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3221225472");
+        assert_eq!(req.header_u64("Content-Length"), Some(3 << 30));
+        assert_eq!(req.content_length(), 3 << 30);
+        assert_eq!(req.header_str("Host"), Some("localhost"));
+        // The text is kept for every header, numbers are read from it on demand
+        assert_eq!(req.header_str("Content-Length"), Some("3221225472"));
+        assert_eq!(req.header_u64("Host"), None);
+        assert_eq!(req.header_u64("X-Missing"), None);
+    }
+
+    #[tokio::test]
+    async fn numeric_header_values_are_still_text() {
+        let (addr, server) = spawn_test_server(Config::default());
+        let response = fetch(
+            addr,
+            "GET /user-agent HTTP/1.1\r\nHost: localhost\r\nUser-Agent: 12345\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "12345");
+        let response = fetch(addr, "GET /echo/a HTTP/1.1\r\nHost: 8080\r\n\r\n").await;
+        assert_eq!(response.status, 200);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn a_panicking_handler_gets_500_and_the_server_carries_on() {
+        let (addr, server) = spawn_test_server(Config::default());
+        let mut client = TestClient::connect(addr).await;
+        client
+            .send("GET /panic HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await;
+        assert_eq!(client.response().await.status, 500);
+        client
+            .send("GET /echo/still HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await;
+        assert_eq!(client.response().await.text(), "still");
+        let response = fetch(addr, "GET /echo/new HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(response.text(), "new");
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn options_asterisk_lists_the_server_methods() {
+        let req = request("OPTIONS * HTTP/1.1\r\nHost: localhost");
+        assert_eq!(req.target, ASTERISK_TARGET);
+        // Only OPTIONS may use asterisk-form
+        let head = "GET * HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert!(HttpRequest::from_str(&head, &Config::default()).is_err());
+        let (addr, server) = spawn_test_server(Config::default());
+        let response = fetch(addr, "OPTIONS * HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(response.status, 200);
+        let allow: Vec<&str> = response.header("allow").unwrap().split(", ").collect();
+        for method in ["GET", "HEAD", "POST", "PUT", "PATCH", "OPTIONS", "TRACE"] {
+            assert!(allow.contains(&method), "{:?}", allow);
+        }
+        server.stop().await;
+    }
+
+    #[test]
+    fn messages_below_the_log_level_are_suppressed() {
+        let args = |level: &str| vec!["--log-level".to_string(), level.to_string()];
+        let config = Config::parse(args("error")).unwrap();
+        assert!(LogLevel::Error.enabled(&config));
+        assert!(!LogLevel::Warn.enabled(&config));
+        assert!(!LogLevel::Info.enabled(&config));
+        assert!(!LogLevel::Debug.enabled(&config));
+        let config = Config::parse(args("DEBUG")).unwrap();
+        assert!(LogLevel::Debug.enabled(&config));
+        assert!(Config::parse(args("loud")).is_err());
+    }
+
+    #[tokio::test]
+    async fn conflicting_singleton_headers_get_400() {
+        for head in [
+            "POST /echo/a HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\nContent-Length: 30",
+            "GET /echo/a HTTP/1.1\r\nHost: localhost\r\nHost: evil.example",
+        ] {
+            let parsed = HttpRequest::from_str(&format!("{}\r\n\r\n", head), &Config::default());
+            assert!(matches!(parsed, Err(HttpResponse::BadRequest)), "{}", head);
+        }
+        let (addr, server) = spawn_test_server(Config::default());
+        let raw = "POST /echo/a HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\nContent-Length: 30\r\n\r\nabc";
+        assert_eq!(fetch(addr, raw).await.status, 400);
+        server.stop().await;
+    }
+
+    #[test]
+    fn header_names_cannot_hold_whitespace() {
+        for head in [
+            "POST /echo/a HTTP/1.1\r\nHost: localhost\r\nContent-Length : 3",
+            "POST /echo/a HTTP/1.1\r\nHost: localhost\r\nContent-Length\t: 3",
+            "GET /echo/a HTTP/1.1\r\nHost: localhost\r\n X-Folded: 1",
+        ] {
+            let parsed = HttpRequest::from_str(&format!("{}\r\n\r\n", head), &Config::default());
+            assert!(
+                matches!(parsed, Err(HttpResponse::BadRequest)),
+                "{:?}",
+                head
+            );
+        }
+        // The colon of an absolute-form target is not taken for a header
+        let req = request("GET http://example.com/echo/a HTTP/1.1\r\nHost: example.com");
+        assert_eq!(req.header_str("Host"), Some("example.com"));
+    }
+
+    #[tokio::test]
+    async fn a_length_next_to_chunked_framing_is_dropped() {
+        let req = request(
+            "POST /files/a HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nContent-Length: 100",
+        );
+        assert_eq!(req.header_str("Content-Length"), None);
+        assert!(req.is_chunked() && !req.keep_alive());
+        let dir = TempDir::new();
+        let (addr, server) = spawn_test_server(serving(&dir));
+        let mut client = TestClient::connect(addr).await;
+        client
+            .send("POST /files/a.txt HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nContent-Length: 100\r\n\r\n4\r\ndata\r\n0\r\n\r\n")
+            .await;
+        let response = client.response().await;
+        assert_eq!(response.status, 201);
+        assert_eq!(response.header("connection"), Some("close"));
+        assert!(client.closed().await);
+        assert_eq!(std::fs::read(dir.0.join("a.txt")).unwrap(), b"data");
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn a_redirect_rule_answers_308() {
+        let args = vec![REDIRECT_ARG.to_string(), "/old=/new".to_string()];
+        let (addr, server) = spawn_test_server(Config::parse(args).unwrap());
+        let response = fetch(addr, "GET /old HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(response.status, 308);
+        assert_eq!(response.header("location"), Some("/new"));
+        // Only the exact path is redirected
+        let response = fetch(addr, "GET /echo/old HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(response.status, 200);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn echo_returns_the_whole_decoded_path() {
+        let req = request("GET /echo/hello%20world HTTP/1.1\r\nHost: localhost");
+        let response = handled(&req, &Config::default()).await;
+        assert!(response.ends_with("\r\n\r\nhello world"), "{}", response);
+        let req = request("GET /echo/a%2Fb/c%09d HTTP/1.1\r\nHost: localhost");
+        let response = handled(&req, &Config::default()).await;
+        assert!(response.ends_with("\r\n\r\na/b/c\td"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn a_truncated_upload_gets_400_and_writes_nothing() {
+        let dir = TempDir::new();
+        let (addr, server) = spawn_test_server(serving(&dir));
+        for method in ["POST", "PUT"] {
+            let mut client = TestClient::connect(addr).await;
+            client
+                .send(format!(
+                    "{} /files/partial.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 100\r\n\r\nonly ten..",
+                    method
+                ))
+                .await;
+            client.stream.shutdown().await.unwrap();
+            assert_eq!(client.response().await.status, 400, "{}", method);
+            let left: Vec<_> = std::fs::read_dir(&dir.0).unwrap().collect();
+            assert!(left.is_empty(), "{:?}", left);
+        }
+        server.stop().await;
+    }
+
+    #[test]
+    fn the_cache_evicts_the_least_recently_used() {
+        let mut cache = FileCache::new();
+        let modified = SystemTime::now();
+        let (a, b, c) = (Path::new("/a"), Path::new("/b"), Path::new("/c"));
+        cache.insert(a, modified, b"aaa", 8);
+        cache.insert(b, modified, b"bbb", 8);
+        assert_eq!(cache.get(a, modified).as_deref(), Some(&b"aaa"[..]));
+        cache.insert(c, modified, b"ccc", 8);
+        assert_eq!(cache.get(b, modified), None);
+        assert!(cache.get(a, modified).is_some() && cache.get(c, modified).is_some());
+        assert_eq!(cache.total_bytes, 6);
+        // A changed modification time invalidates the entry
+        assert_eq!(cache.get(a, modified + Duration::from_secs(1)), None);
+        // Files larger than the whole cache are never kept
+        cache.insert(Path::new("/big"), modified, &[0; 9], 8);
+        assert_eq!(cache.get(Path::new("/big"), modified), None);
+    }
+
+    #[tokio::test]
+    async fn a_rewritten_file_is_read_again() {
+        let dir = TempDir::new();
+        dir.write("cached.txt", "first");
+        let (addr, server) = spawn_test_server(Config {
+            cache_bytes: 1 << 20,
+            ..serving(&dir)
+        });
+        let get = "GET /files/cached.txt HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert_eq!(fetch(addr, get).await.text(), "first");
+        assert_eq!(fetch(addr, get).await.text(), "first");
+        dir.write("cached.txt", "second");
+        // Make sure the rewrite is visible even on a coarse-grained clock
+        let file = std::fs::File::options()
+            .write(true)
+            .open(dir.0.join("cached.txt"))
+            .unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(fetch(addr, get).await.text(), "second");
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn patch_rewrites_only_the_given_range() {
+        let dir = TempDir::new();
+        dir.write("doc.txt", "abcdefghij");
+        let (addr, server) = spawn_test_server(serving(&dir));
+        let patch = |name: &str, range: &str, body: &str| {
+            format!(
+                "PATCH /files/{} HTTP/1.1\r\nHost: localhost\r\nContent-Range: bytes {}\r\nContent-Length: {}\r\n\r\n{}",
+                name,
+                range,
+                body.len(),
+                body
+            )
+        };
+        assert_eq!(
+            fetch(addr, patch("doc.txt", "4-6/10", "XYZ")).await.status,
+            204
+        );
+        let doc = dir.0.join("doc.txt");
+        assert_eq!(std::fs::read_to_string(&doc).unwrap(), "abcdXYZhij");
+        // Past the end the file is extended
+        assert_eq!(
+            fetch(addr, patch("doc.txt", "10-11/12", "kl")).await.status,
+            204
+        );
+        assert_eq!(std::fs::read_to_string(&doc).unwrap(), "abcdXYZhijkl");
+        assert_eq!(
+            fetch(addr, patch("missing.txt", "0-1/2", "ab"))
+                .await
+                .status,
+            404
+        );
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn a_request_line_needs_exactly_three_tokens() {
+        for line in [
+            "GET/foo HTTP/1.1",
+            "GET /foo",
+            "GET /foo HTTP/1.1 extra",
+            "GET /foo HTTP/11",
+        ] {
+            assert!(request_line_tokens(line).is_none(), "{}", line);
+        }
+        let (addr, server) = spawn_test_server(Config::default());
+        for line in ["GET/echo/a HTTP/1.1", "GET /echo/a HTTP/1.1 extra"] {
+            let raw = format!("{}\r\nHost: localhost\r\n\r\n", line);
+            assert_eq!(fetch(addr, raw).await.status, 400, "{}", line);
+        }
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn an_empty_file_has_a_zero_length() {
+        let dir = TempDir::new();
+        dir.write("empty.txt", "");
+        let (addr, server) = spawn_test_server(serving(&dir));
+        for method in ["GET", "HEAD"] {
+            let mut client = TestClient::connect(addr).await;
+            let raw = format!(
+                "{} /files/empty.txt HTTP/1.1\r\nHost: localhost\r\n\r\n",
+                method
+            );
+            client.send(raw).await;
+            let response = client.response().await;
+            assert_eq!(response.status, 200);
+            assert_eq!(response.header("content-length"), Some("0"));
+            assert_eq!(
+                response.header("content-type"),
+                Some("text/plain; charset=utf-8")
+            );
+            assert!(response.body.is_empty());
+            let names: Vec<&str> = response
+                .headers
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect();
+            assert_eq!(
+                names,
+                [
+                    "Content-Type",
+                    "Content-Length",
+                    "Accept-Ranges",
+                    "ETag",
+                    "Last-Modified",
+                    "Keep-Alive"
+                ]
+            );
+            // Nothing follows the headers, the connection is ready for the next request
+            client
+                .send("GET /echo/next HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await;
+            assert_eq!(client.response().await.text(), "next");
+        }
+        server.stop().await;
+    }
+
+    // A counter from the /metrics report
+    async fn metric(addr: SocketAddr, name: &str) -> u64 {
+        let response = fetch(addr, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        let text = response.text();
+        text.lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_else(|| panic!("no {} in {}", name, text))
+    }
+
+    #[tokio::test]
+    async fn metrics_count_the_requests_served() {
+        let (addr, server) = spawn_test_server(Config::default());
+        // The counters are shared with tests running alongside, so only lower bounds hold
+        let (total, not_found) = (
+            metric(addr, "requests_total").await,
+            metric(addr, "responses_4xx").await,
+        );
+        for path in ["echo/a", "echo/b", "missing"] {
+            let raw = format!("GET /{} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+            fetch(addr, raw).await;
+        }
+        assert!(metric(addr, "requests_total").await >= total + 4);
+        assert!(metric(addr, "responses_4xx").await > not_found);
+        assert!(metric(addr, "bytes_served").await > 0);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn a_small_read_buffer_keeps_uploads_intact() {
+        let args = |bytes: &str| vec![READ_BUFFER_ARG.to_string(), bytes.to_string()];
+        assert!(Config::parse(args("0")).is_err());
+        assert!(Config::parse(args("63")).is_err());
+        let dir = TempDir::new();
+        let (addr, server) = spawn_test_server(Config {
+            directory: Some(dir.path()),
+            ..Config::parse(args("64")).unwrap()
+        });
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let mut raw = format!(
+            "POST /files/upload.bin HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n",
+            data.len()
+        )
+        .into_bytes();
+        raw.extend_from_slice(&data);
+        assert_eq!(fetch(addr, raw).await.status, 201);
+        assert_eq!(std::fs::read(dir.0.join("upload.bin")).unwrap(), data);
+        let response = fetch(
+            addr,
+            "GET /files/upload.bin HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.body, data);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn only_compressed_responses_vary_on_accept_encoding() {
+        let (addr, server) = spawn_test_server(Config::default());
+        let path = format!("/echo/{}", "a".repeat(4096));
+        let get =
+            |accept: &str| format!("GET {} HTTP/1.1\r\nHost: localhost\r\n{}\r\n", path, accept);
+        let response = fetch(addr, get("Accept-Encoding: gzip\r\n")).await;
+        assert_eq!(response.header("content-encoding"), Some("gzip"));
+        assert_eq!(response.header("vary"), Some("Accept-Encoding"));
+        let response = fetch(addr, get("")).await;
+        assert_eq!(response.header("content-encoding"), None);
+        assert_eq!(response.header("vary"), None);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn writes_honour_if_match() {
+        let dir = TempDir::new();
+        dir.write("shared.txt", "v1");
+        let (addr, server) = spawn_test_server(serving(&dir));
+        let get = "GET /files/shared.txt HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let etag = fetch(addr, get).await.header("etag").unwrap().to_string();
+        let write = |method: &str, condition: &str, body: &str| {
+            format!(
+                "{} /files/shared.txt HTTP/1.1\r\nHost: localhost\r\n{}\r\nContent-Length: {}\r\n\r\n{}",
+                method,
+                condition,
+                body.len(),
+                body
+            )
+        };
+        let stale = write("PUT", "If-Match: \"stale\"", "lost");
+        assert_eq!(fetch(addr, stale).await.status, 412);
+        let current = write("PUT", &format!("If-Match: {}", etag), "v2");
+        assert_eq!(fetch(addr, current).await.status, 201);
+        assert_eq!(fetch(addr, get).await.text(), "v2");
+        // The tag has changed with the contents
+        assert_eq!(
+            fetch(addr, write("POST", &format!("If-Match: {}", etag), "v3"))
+                .await
+                .status,
+            412
+        );
+        assert_eq!(
+            fetch(addr, write("POST", "If-None-Match: *", "v3"))
+                .await
+                .status,
+            412
+        );
+        assert_eq!(fetch(addr, get).await.text(), "v2");
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn data_after_connection_close_does_not_reset_the_response() {
+        let (addr, server) = spawn_test_server(Config::default());
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut raw =
+            b"GET /echo/last HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n".to_vec();
+        raw.extend_from_slice(b"GET /echo/ignored HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        raw.extend_from_slice(&[b'x'; 32 * 1024]);
+        stream.write_all(&raw).await.unwrap();
+        let mut received = Vec::new();
+        // A reset would fail the read instead of ending it
+        time::timeout(TEST_TIMEOUT, stream.read_to_end(&mut received))
+            .await
+            .expect("the server did not close the connection")
+            .unwrap();
+        let received = String::from_utf8(received).unwrap();
+        assert!(received.starts_with("HTTP/1.1 200 OK\r\n"), "{}", received);
+        assert!(received.ends_with("\r\n\r\nlast"), "{}", received);
+        server.stop().await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlinks_are_refused_when_not_followed() {
+        let (dir, outside) = (TempDir::new(), TempDir::new());
+        outside.write("secret.txt", "secret");
+        std::os::unix::fs::symlink(outside.0.join("secret.txt"), dir.0.join("link.txt")).unwrap();
+        std::os::unix::fs::symlink(&outside.0, dir.0.join("linked-dir")).unwrap();
+        let get = |name: &str| format!("GET /files/{} HTTP/1.1\r\nHost: localhost\r\n\r\n", name);
+        let (addr, server) = spawn_test_server(serving(&dir));
+        assert_eq!(fetch(addr, get("link.txt")).await.text(), "secret");
+        server.stop().await;
+        let (addr, server) = spawn_test_server(Config {
+            no_follow_symlinks: true,
+            ..serving(&dir)
+        });
+        assert_eq!(fetch(addr, get("link.txt")).await.status, 404);
+        assert_eq!(fetch(addr, get("linked-dir/secret.txt")).await.status, 404);
+        server.stop().await;
+    }
+
+    // A directory to serve holding `name` as a symlink to a secret outside of it
+    fn linking_out(name: &str) -> (TempDir, TempDir) {
+        let (dir, outside) = (TempDir::new(), TempDir::new());
+        outside.write("secret.html", "secret");
+        std::os::unix::fs::symlink(outside.0.join("secret.html"), dir.0.join(name)).unwrap();
+        (dir, outside)
+    }
+
+    #[tokio::test]
+    async fn a_linked_index_is_not_followed() {
+        let (dir, outside) = linking_out(INDEX_PAGE);
+        let get = "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let (addr, server) = spawn_test_server(Config {
+            no_follow_symlinks: true,
+            ..serving(&dir)
+        });
+        let response = fetch(addr, get).await;
+        assert_eq!(response.status, 200);
+        assert!(response.body.is_empty(), "{}", response.text());
+        server.stop().await;
+        // Nor is an `--index-file` that is itself a link
+        let index_file = dir.0.join("linked-index.html");
+        std::os::unix::fs::symlink(outside.0.join("secret.html"), &index_file).unwrap();
+        let (addr, server) = spawn_test_server(Config {
+            no_follow_symlinks: true,
+            index_file: Some(index_file.to_string_lossy().into_owned()),
+            ..Config::default()
+        });
+        assert_eq!(fetch(addr, get).await.status, 404);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn a_linked_not_found_page_is_not_followed() {
+        let (dir, _outside) = linking_out(NOT_FOUND_PAGE);
+        let get = "GET /files/missing.txt HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let (addr, server) = spawn_test_server(serving(&dir));
+        assert_eq!(fetch(addr, get).await.text(), "secret");
+        server.stop().await;
+        let (addr, server) = spawn_test_server(Config {
+            no_follow_symlinks: true,
+            ..serving(&dir)
+        });
+        let response = fetch(addr, get).await;
+        assert_eq!(response.status, 404);
+        assert!(response.body.is_empty(), "{}", response.text());
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn a_linked_spa_index_is_not_followed() {
+        let (dir, _outside) = linking_out(INDEX_PAGE);
+        let (addr, server) = spawn_test_server(Config {
+            no_follow_symlinks: true,
+            spa_fallback: true,
+            ..serving(&dir)
+        });
+        let response = fetch(addr, "GET /app/route HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(response.status, 404);
+        assert_ne!(response.text(), "secret");
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn unknown_units_and_malformed_ranges_get_the_whole_file() {
+        let dir = TempDir::new();
+        dir.write("digits.txt", "0123456789");
+        let (addr, server) = spawn_test_server(serving(&dir));
+        for range in [
+            "items=0-4",
+            "bytes=abc-def",
+            "bytes=",
+            "bytes=5-2",
+            "bytes 0-4",
+        ] {
+            let raw = format!(
+                "GET /files/digits.txt HTTP/1.1\r\nHost: localhost\r\nRange: {}\r\n\r\n",
+                range
+            );
+            let response = fetch(addr, raw).await;
+            assert_eq!(
+                (response.status, response.text().as_str()),
+                (200, "0123456789"),
+                "{}",
+                range
+            );
+        }
+        server.stop().await;
+    }
+
+    #[test]
+    fn placeholders_are_substituted() {
+        let vars = HashMap::from([
+            ("NAME".to_string(), "world".to_string()),
+            ("EMPTY".to_string(), String::new()),
+        ]);
+        for (template, rendered) in [
+            ("Hello {{NAME}}!", "Hello world!"),
+            ("{{NAME}}{{NAME}}", "worldworld"),
+            ("[{{EMPTY}}]", "[]"),
+            ("{{UNKNOWN}} stays", "{{UNKNOWN}} stays"),
+            ("{{NAME", "{{NAME"),
+            ("{{ {{NAME}}", "{{ {{NAME}}"),
+            ("no placeholders", "no placeholders"),
+        ] {
+            assert_eq!(render_template(template, &vars), rendered, "{}", template);
+        }
+    }
+
+    #[tokio::test]
+    async fn templated_html_gets_the_host() {
+        let dir = TempDir::new();
+        let page = "<p>Served by {{HOST}} for {{WHO}}, {{UNSET}}</p>";
+        dir.write("page.html", page);
+        dir.write("page.txt", page);
+        let mut args = vec![TEMPLATING_ARG.to_string()];
+        args.extend([TEMPLATE_VAR_ARG.to_string(), "WHO=you".to_string()]);
+        let (addr, server) = spawn_test_server(Config {
+            directory: Some(dir.path()),
+            ..Config::parse(args).unwrap()
+        });
+        let get =
+            |name: &str| format!("GET /files/{} HTTP/1.1\r\nHost: example.test\r\n\r\n", name);
+        assert_eq!(
+            fetch(addr, get("page.html")).await.text(),
+            "<p>Served by example.test for you, {{UNSET}}</p>"
+        );
+        // Only HTML is rendered
+        assert_eq!(fetch(addr, get("page.txt")).await.text(), page);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn connections_past_a_full_queue_get_503() {
+        let (addr, server) = spawn_test_server(Config {
+            workers: Some(1),
+            queue_capacity: 1,
+            ..Config::default()
+        });
+        let get = "GET /echo/a HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        // A kept-alive connection holds the one worker
+        let mut busy = TestClient::connect(addr).await;
+        busy.send(get).await;
+        assert_eq!(busy.response().await.status, 200);
+        let mut queued = TestClient::connect(addr).await;
+        queued.send(get).await;
+        time::sleep(Duration::from_millis(200)).await;
+        let refused = fetch(addr, get).await;
+        assert_eq!(refused.status, 503);
+        assert!(refused.header("retry-after").is_some());
+        // Once the worker is free the queued connection is served
+        drop(busy);
+        assert_eq!(queued.response().await.status, 200);
+        server.stop().await;
+    }
+
+    #[test]
+    fn host_headers_split_into_name_and_port() {
+        let host = |name: &str, port: Option<u16>| Some((name.to_string(), port));
+        assert_eq!(parse_host("example.com"), host("example.com", None));
+        assert_eq!(
+            parse_host("example.com:4221"),
+            host("example.com", Some(4221))
+        );
+        assert_eq!(parse_host("[::1]:8080"), host("::1", Some(8080)));
+        assert_eq!(parse_host("[::1]"), host("::1", None));
+        assert_eq!(
+            parse_host(" example.com.:80 "),
+            host("example.com", Some(80))
+        );
+        for malformed in [
+            "",
+            ":80",
+            "example.com:http",
+            "example.com:99999",
+            "[::1",
+            "[nope]:80",
+            "a b",
+        ] {
+            assert_eq!(parse_host(malformed), None, "{:?}", malformed);
+        }
+    }
+
+    #[tokio::test]
+    async fn default_headers_are_added_to_every_response() {
+        let args: Vec<String> = [
+            HEADER_ARG,
+            "X-Content-Type-Options: nosniff",
+            HEADER_ARG,
+            "X-Frame-Options: DENY",
+        ]
+        .map(str::to_string)
+        .into();
+        let (addr, server) = spawn_test_server(Config::parse(args).unwrap());
+        let response = fetch(addr, "GET /echo/a HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(response.header("x-content-type-options"), Some("nosniff"));
+        assert_eq!(response.header("x-frame-options"), Some("DENY"));
+        server.stop().await;
+        let malformed = vec![HEADER_ARG.to_string(), "no colon here".to_string()];
+        assert!(Config::parse(malformed).is_err());
+    }
+
+    #[tokio::test]
+    async fn a_get_body_is_consumed_before_the_next_request() {
+        let (addr, server) = spawn_test_server(Config::default());
+        let mut client = TestClient::connect(addr).await;
+        // The body looks like a request of its own, it must not be parsed as one
+        let body = "GET /echo/smuggled HTTP/1.1\r\n\r\n";
+        client
+            .send(format!(
+                "GET /echo/first HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}\
+                 GET /echo/second HTTP/1.1\r\nHost: localhost\r\n\r\n",
+                body.len(),
+                body
+            ))
+            .await;
+        assert_eq!(client.response().await.text(), "first");
+        assert_eq!(client.response().await.text(), "second");
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn content_length_counts_the_final_bytes() {
+        let req = request("GET /echo/h%C3%A9llo HTTP/1.1\r\nHost: localhost");
+        let response = handled(&req, &Config::default()).await;
+        assert_eq!(header_lines(&response, "Content-Length"), ["6"]);
+        assert!(response.ends_with("\r\n\r\nhéllo"), "{}", response);
+        // A compressed body is measured after compression
+        let (addr, server) = spawn_test_server(Config::default());
+        let raw = format!(
+            "GET /echo/{} HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip\r\n\r\n",
+            "é".repeat(1000).replace('é', "%C3%A9")
+        );
+        let response = fetch(addr, raw).await;
+        assert_eq!(response.header("content-encoding"), Some("gzip"));
+        let length: usize = response.header("content-length").unwrap().parse().unwrap();
+        assert_eq!(length, response.body.len());
+        let mut decoded = String::new();
+        io::Read::read_to_string(&mut GzDecoder::new(&response.body[..]), &mut decoded).unwrap();
+        assert_eq!(decoded, "é".repeat(1000));
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn the_admin_port_reports_stats_and_shuts_down() {
+        let (addr, handle) = spawn_test_server(Config::default());
+        let admin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let admin_addr = admin.local_addr().unwrap();
+        tokio::spawn(serve_admin(admin, handle.server.clone()));
+        let mut client = TestClient::connect(admin_addr).await;
+        client.send("STATS\n").await;
+        assert!(client.until(b"\n").await.starts_with("requests_total "));
+        client.send("bogus\n").await;
+        while client.until(b"\n").await != "ERR unknown command" {}
+        client.send("shutdown\n").await;
+        assert_eq!(client.until(b"\n").await, "OK");
+        time::timeout(TEST_TIMEOUT, handle.running)
+            .await
+            .expect("the server did not stop")
+            .unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn an_unsatisfiable_range_reports_the_size() {
+        let dir = TempDir::new();
+        dir.write("ten.txt", "0123456789");
+        let (addr, server) = spawn_test_server(serving(&dir));
+        let raw = "GET /files/ten.txt HTTP/1.1\r\nHost: localhost\r\nRange: bytes=10-20\r\n\r\n";
+        let response = fetch(addr, raw).await;
+        assert_eq!(response.status, 416);
+        assert_eq!(response.header("content-range"), Some("bytes */10"));
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn the_index_file_is_served_at_the_root() {
+        let dir = TempDir::new();
+        dir.write("index.html", "<h1>Home</h1>");
+        let index_file = format!("{}/index.html", dir.path());
+        let (addr, server) = spawn_test_server(Config {
+            index_file: Some(index_file.clone()),
+            ..Config::default()
+        });
+        let get = "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let response = fetch(addr, get).await;
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.header("content-type"),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(response.text(), "<h1>Home</h1>");
+        std::fs::remove_file(&index_file).unwrap();
+        assert_eq!(fetch(addr, get).await.status, 404);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn unmatched_paths_fall_back_to_the_spa_index() {
+        let dir = TempDir::new();
+        dir.write("index.html", "<div id=app></div>");
+        dir.write("app.js", "boot()");
+        let (addr, server) = spawn_test_server(Config {
+            spa_fallback: true,
+            ..serving(&dir)
+        });
+        let get = |path: &str| format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        for path in ["/dashboard/settings", "/files/missing.js"] {
+            let response = fetch(addr, get(path)).await;
+            assert_eq!(
+                (response.status, response.text().as_str()),
+                (200, "<div id=app></div>"),
+                "{}",
+                path
+            );
+        }
+        assert_eq!(fetch(addr, get("/files/app.js")).await.text(), "boot()");
+        for path in ["/api", "/api/users"] {
+            assert_eq!(fetch(addr, get(path)).await.status, 404, "{}", path);
+        }
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn a_gzipped_upload_is_stored_decompressed() {
+        let dir = TempDir::new();
+        let (addr, server) = spawn_test_server(serving(&dir));
+        let original = "line of text\n".repeat(100);
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(original.as_bytes()).unwrap();
+        let post = |name: &str, body: &[u8]| {
+            let mut raw = format!(
+                "POST /files/{} HTTP/1.1\r\nHost: localhost\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+                name,
+                body.len()
+            )
+            .into_bytes();
+            raw.extend_from_slice(body);
+            raw
+        };
+        let response = fetch(addr, post("text.txt", &encoder.finish().unwrap())).await;
+        assert_eq!(response.status, 201);
+        assert_eq!(
+            std::fs::read_to_string(dir.0.join("text.txt")).unwrap(),
+            original
+        );
+        let response = fetch(addr, post("broken.txt", b"not gzip at all")).await;
+        assert_eq!(response.status, 400);
+        assert!(!dir.0.join("broken.txt").exists());
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn a_trickled_request_times_out_as_a_whole() {
+        let (addr, server) = spawn_test_server(Config {
+            request_timeout: Duration::from_millis(500),
+            ..Config::default()
+        });
+        let mut client = TestClient::connect(addr).await;
+        let started = time::Instant::now();
+        // Each byte comes well within any single read's timeout
+        for byte in b"GET /echo/slow HTTP/1.1\r\nHost: localhost\r\nX-Padding: abcdefghijklmnop\r\n"
+        {
+            let answered = time::timeout(Duration::from_millis(100), client.stream.readable());
+            if answered.await.is_ok() {
+                break;
+            }
+            client.stream.write_all(&[*byte]).await.unwrap();
+        }
+        assert_eq!(client.response().await.status, 408);
+        assert!(
+            started.elapsed() < Duration::from_secs(2),
+            "{:?}",
+            started.elapsed()
+        );
+        server.stop().await;
+    }
+
+    #[test]
+    fn a_trusted_proxy_names_the_logged_client() {
+        let req =
+            request("GET / HTTP/1.1\r\nHost: localhost\r\nX-Forwarded-For: 203.0.113.9, 10.0.0.1");
+        let remote = "10.0.0.1:40000".parse().ok();
+        let logged_client = |config: &Config| {
+            let mut sink = Vec::new();
+            log_access(&mut sink, remote, Some(&req), 200, 0, config);
+            let line = String::from_utf8(sink).unwrap();
+            line.split_once(' ').unwrap().0.to_string()
+        };
+        let trusting = Config {
+            trust_proxy: true,
+            ..Config::default()
+        };
+        assert_eq!(logged_client(&trusting), "203.0.113.9");
+        // Without --trust-proxy the header could be spoofed by anyone
+        assert_eq!(logged_client(&Config::default()), "10.0.0.1");
+        let direct = request("GET / HTTP/1.1\r\nHost: localhost");
+        let mut sink = Vec::new();
+        log_access(&mut sink, remote, Some(&direct), 200, 0, &trusting);
+        assert!(String::from_utf8(sink).unwrap().starts_with("10.0.0.1 "));
+    }
+
+    #[tokio::test]
+    async fn connections_past_the_cap_are_refused() {
+        let (addr, server) = spawn_test_server(Config {
+            max_connections: Some(2),
+            ..Config::default()
+        });
+        let mut open = Vec::new();
+        for _ in 0..2 {
+            open.push(TestClient::connect(addr).await);
+        }
+        let mut client = TestClient::connect(addr).await;
+        let response = client.response().await;
+        assert_eq!(response.status, 503);
+        assert!(response.header("retry-after").is_some());
+        assert!(client.closed().await);
+        // A slot frees up once a connection closes
+        drop(client);
+        drop(open.pop());
+        time::sleep(Duration::from_millis(200)).await;
+        let response = fetch(addr, "GET /echo/a HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(response.status, 200);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn options_lists_the_methods_of_the_route() {
+        let dir = TempDir::new();
+        let (addr, server) = spawn_test_server(serving(&dir));
+        for (target, allow) in [
+            ("/echo/x", "GET, HEAD, OPTIONS"),
+            ("/files/y", "GET, HEAD, POST, PUT, PATCH, OPTIONS"),
+            ("/upload", "POST, OPTIONS"),
+        ] {
+            let raw = format!("OPTIONS {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target);
+            let response = fetch(addr, raw).await;
+            assert_eq!(response.status, 204, "{}", target);
+            assert_eq!(response.header("allow"), Some(allow), "{}", target);
+        }
+        let response = fetch(addr, "OPTIONS /nowhere HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(response.status, 404);
+        server.stop().await;
+    }
+
+    // Parses the space separated command line `cli`
+    fn parsed(cli: &str) -> Result<Config, String> {
+        Config::parse(cli.split_whitespace().map(str::to_string).collect())
+    }
+
+    #[test]
+    fn flags_override_the_defaults() {
+        let config = Config::default();
+        assert_eq!(config.port, DEFAULT_PORT);
+        assert_eq!(config.bind, DEFAULT_BIND);
+        assert_eq!(config.directory, None);
+        assert_eq!(config.keepalive_timeout, DEFAULT_KEEPALIVE_TIMEOUT);
+        assert_eq!(config.read_buffer_bytes, DEFAULT_READ_BUFFER_BYTES);
+        assert_eq!(config.workers, None);
+        let config =
+            parsed("--port 8080 --directory /srv --keepalive-timeout 9 --workers 4").unwrap();
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.directory.as_deref(), Some("/srv"));
+        assert_eq!(config.keepalive_timeout, Duration::from_secs(9));
+        assert_eq!(config.workers, Some(4));
+        // The last occurrence of a flag wins
+        assert_eq!(parsed("--port 1 --port 2").unwrap().port, 2);
+        assert!(parsed("--port http").is_err());
+        for port in ["0", "65536", "-1"] {
+            assert_eq!(
+                parsed(&format!("--port {}", port)).err().unwrap(),
+                format!("--port must be a port between 1 and 65535, got {}", port)
+            );
+        }
+        assert!(parsed("--admin-port 0").is_err());
+        assert_eq!(
+            parsed("--admin-port 65535").unwrap().admin_port,
+            Some(65535)
+        );
+        assert!(parsed("--read-buffer 1").is_err());
+        assert!(parsed("--log-level loud").is_err());
+        assert!(parsed("--header NoColon").is_err());
+    }
+
+    #[test]
+    fn globs_match_whole_names() {
+        assert!(glob_match("*.env", ".env"));
+        assert!(glob_match("*.env", "prod.env"));
+        assert!(!glob_match("*.env", "prod.env.txt"));
+        assert!(glob_match("?.txt", "a.txt"));
+        assert!(!glob_match("?.txt", "ab.txt"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("a*b*c", "aXbYbZ"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("exact", "exact"));
+        assert!(!glob_match("exact", "exactly"));
+    }
+
+    #[tokio::test]
+    async fn denied_names_are_hidden_and_others_served() {
+        let dir = TempDir::new();
+        dir.write(".env", "SECRET=1");
+        dir.write("notes.txt", "notes");
+        dir.write("server.key", "key");
+        let (addr, server) = spawn_test_server(Config {
+            deny: vec!["*.env".to_string(), "*.key".to_string()],
+            ..serving(&dir)
+        });
+        for hidden in [".env", "server.key"] {
+            let raw = format!("GET /files/{} HTTP/1.1\r\nHost: localhost\r\n\r\n", hidden);
+            assert_eq!(fetch(addr, raw).await.status, 404, "{}", hidden);
+        }
+        let response = fetch(
+            addr,
+            "GET /files/notes.txt HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "notes");
+        server.stop().await;
+        // An allow list hides everything it does not name
+        let (addr, server) = spawn_test_server(Config {
+            allow: vec!["*.txt".to_string()],
+            ..serving(&dir)
+        });
+        let response = fetch(
+            addr,
+            "GET /files/server.key HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.status, 404);
+        let response = fetch(
+            addr,
+            "GET /files/notes.txt HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.status, 200);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn continue_is_sent_before_the_chunks_are_read() {
+        let dir = TempDir::new();
+        let (addr, server) = spawn_test_server(serving(&dir));
+        let mut client = TestClient::connect(addr).await;
+        client
+            .send("POST /files/chunked.txt HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\nTransfer-Encoding: chunked\r\n\r\n")
+            .await;
+        // Nothing of the body has been sent while the interim response is awaited
+        assert_eq!(client.response().await.status, 100);
+        client.send("4\r\nchun\r\n3\r\nked\r\n0\r\n\r\n").await;
+        assert_eq!(client.response().await.status, 201);
+        assert_eq!(
+            std::fs::read(dir.0.join("chunked.txt")).unwrap(),
+            b"chunked"
+        );
+        server.stop().await;
+    }
+
+    #[test]
+    fn quiet_leaves_only_errors_and_a_requested_access_log() {
+        let req = request("GET / HTTP/1.1\r\nHost: localhost");
+        let remote = "10.0.0.7:51234".parse().ok();
+        let logged = |config: &Config| {
+            let mut sink = Vec::new();
+            log_access(&mut sink, remote, Some(&req), 200, 0, config);
+            !sink.is_empty()
+        };
+        // Both writers to stdout, `log_info!`/`log_debug!` and the access log, are off
+        let quiet = parsed("--quiet").unwrap();
+        assert!(!LogLevel::Info.enabled(&quiet));
+        assert!(!LogLevel::Debug.enabled(&quiet));
+        assert!(!LogLevel::Warn.enabled(&quiet));
+        assert!(LogLevel::Error.enabled(&quiet));
+        assert!(!logged(&quiet));
+        assert!(logged(&Config::default()));
+        // The access log is chosen on its own
+        let logging = parsed("--quiet --access-log").unwrap();
+        assert!(!LogLevel::Info.enabled(&logging));
+        assert!(logged(&logging));
+    }
+
+    #[tokio::test]
+    async fn a_br_sibling_is_preferred_over_gz() {
+        let dir = TempDir::new();
+        // Siblings are sent as they are, so their bytes need not really be compressed
+        dir.write("app.js", "console.log(1);");
+        dir.write("app.js.br", b"brotli bytes");
+        dir.write("app.js.gz", b"gzip bytes");
+        let (addr, server) = spawn_test_server(serving(&dir));
+        let get = "GET /files/app.js HTTP/1.1\r\nHost: localhost\r\n";
+        let response = fetch(addr, format!("{}Accept-Encoding: gzip, br\r\n\r\n", get)).await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("content-encoding"), Some("br"));
+        assert_eq!(
+            response.header("content-type"),
+            Some("text/javascript; charset=utf-8")
+        );
+        assert_eq!(response.body, b"brotli bytes");
+        let response = fetch(addr, format!("{}Accept-Encoding: gzip\r\n\r\n", get)).await;
+        assert_eq!(response.header("content-encoding"), Some("gzip"));
+        assert_eq!(response.body, b"gzip bytes");
+        server.stop().await;
+    }
+
+    #[test]
+    fn connection_lists_are_split_into_tokens() {
+        let req = request("GET / HTTP/1.1\r\nHost: localhost\r\nConnection: keep-alive, Upgrade");
+        assert_eq!(
+            req.connection_tokens(),
+            HashSet::from(["keep-alive".to_string(), "upgrade".to_string()])
+        );
+        assert!(req.keep_alive());
+        assert!(
+            !request("GET / HTTP/1.1\r\nHost: localhost\r\nConnection: foo, Close").keep_alive()
+        );
+        assert!(request("GET / HTTP/1.0\r\nConnection: Keep-Alive, foo").keep_alive());
+        assert!(!request("GET / HTTP/1.0\r\nConnection: foo").keep_alive());
+    }
+
+    #[tokio::test]
+    async fn a_listed_keep_alive_keeps_the_connection() {
+        let (addr, server) = spawn_test_server(Config::default());
+        let mut client = TestClient::connect(addr).await;
+        let get =
+            "GET /echo/a HTTP/1.1\r\nHost: localhost\r\nConnection: keep-alive, Upgrade\r\n\r\n";
+        for _ in 0..2 {
+            client.send(get).await;
+            let response = client.response().await;
+            assert_eq!(response.status, 200);
+            assert_ne!(response.header("connection"), Some("close"));
+        }
+        client
+            .send("GET /echo/b HTTP/1.1\r\nHost: localhost\r\nConnection: close, foo\r\n\r\n")
+            .await;
+        assert_eq!(client.response().await.header("connection"), Some("close"));
+        assert!(client.closed().await);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn chunks_past_the_body_limit_get_413() {
+        let dir = TempDir::new();
+        let (addr, server) = spawn_test_server(Config {
+            max_body_bytes: Some(10),
+            ..serving(&dir)
+        });
+        let post = |name: &str, chunks: &str| {
+            format!(
+                "POST /files/{} HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n{}",
+                name, chunks
+            )
+        };
+        let response = fetch(
+            addr,
+            post(
+                "big.txt",
+                "4\r\naaaa\r\n4\r\nbbbb\r\n4\r\ncccc\r\n0\r\n\r\n",
+            ),
+        )
+        .await;
+        assert_eq!(response.status, 413);
+        assert!(!dir.0.join("big.txt").exists());
+        // A huge chunk is refused from its size line alone
+        let response = fetch(addr, post("huge.txt", "ffffffff\r\n")).await;
+        assert_eq!(response.status, 413);
+        let response = fetch(
+            addr,
+            post("small.txt", "4\r\naaaa\r\n4\r\nbbbb\r\n0\r\n\r\n"),
+        )
+        .await;
+        assert_eq!(response.status, 201);
+        server.stop().await;
+        // A size that does not even fit in a usize is malformed
+        let (addr, server) = spawn_test_server(serving(&dir));
+        let response = fetch(addr, post("overflow.txt", "fffffffffffffffffff\r\n")).await;
+        assert_eq!(response.status, 400);
+        server.stop().await;
+    }
+
+    #[test]
+    fn only_large_uncompressed_bodies_are_worth_compressing() {
+        let gzip = request("GET / HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip");
+        let config = Config {
+            gzip_min_size: 100,
+            ..Config::default()
+        };
+        assert!(!worth_compressing(&gzip, "text/plain", 99, &config));
+        assert!(worth_compressing(&gzip, "text/plain", 100, &config));
+        assert!(!worth_compressing(&gzip, "image/png", 10_000, &config));
+        assert!(!worth_compressing(
+            &gzip,
+            "application/zip",
+            10_000,
+            &config
+        ));
+        assert!(worth_compressing(&gzip, "image/svg+xml", 10_000, &config));
+        // Without identity to fall back on, even a tiny body is encoded
+        let no_identity =
+            request("GET / HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip, identity;q=0");
+        assert!(worth_compressing(&no_identity, "text/plain", 1, &config));
+    }
+
+    #[tokio::test]
+    async fn short_echoes_are_sent_uncompressed() {
+        let (addr, server) = spawn_test_server(Config::default());
+        let get = |body: &str| {
+            format!(
+                "GET /echo/{} HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip\r\n\r\n",
+                body
+            )
+        };
+        let response = fetch(addr, get("short")).await;
+        assert_eq!(response.header("content-encoding"), None);
+        assert_eq!(response.text(), "short");
+        let long = "a".repeat(DEFAULT_GZIP_MIN_SIZE);
+        let response = fetch(addr, get(&long)).await;
+        assert_eq!(response.header("content-encoding"), Some("gzip"));
+        let mut decoded = String::new();
+        io::Read::read_to_string(&mut GzDecoder::new(&response.body[..]), &mut decoded).unwrap();
+        assert_eq!(decoded, long);
+        server.stop().await;
+    }
+
+    #[test]
+    fn accept_encoding_qualities_and_the_wildcard() {
+        let accepting = |header: &str| {
+            request(&format!(
+                "GET / HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: {}",
+                header
+            ))
+        };
+        let negotiated =
+            |header: &str| negotiate_encoder(&accepting(header)).map(|encoder| encoder.name);
+        assert_eq!(encoding_quality(&accepting("gzip;q=0.5"), GZIP), Some(0.5));
+        assert_eq!(encoding_quality(&accepting("GZIP"), GZIP), Some(1.0));
+        assert_eq!(encoding_quality(&accepting("*;q=0.2"), GZIP), Some(0.2));
+        assert_eq!(encoding_quality(&accepting("br"), GZIP), None);
+        assert_eq!(encoding_quality(&request("GET / HTTP/1.0"), GZIP), None);
+        // The server's own preference settles a wildcard or a tie
+        assert_eq!(negotiated("*"), Some(GZIP));
+        assert_eq!(negotiated("br, gzip"), Some(GZIP));
+        assert_eq!(negotiated("br"), Some(BROTLI));
+        assert_eq!(negotiated("gzip;q=0.5, br"), Some(BROTLI));
+        assert_eq!(negotiated("gzip"), Some(GZIP));
+        assert_eq!(negotiated("gzip;q=0.5, br;q=0.4"), Some(GZIP));
+        assert_eq!(negotiated("gzip;q=0"), None);
+        assert_eq!(negotiated("*;q=0, gzip"), Some(GZIP));
+        assert_eq!(negotiated("gzip;q=0, *"), Some(BROTLI));
+        assert!(!accepts_encoding(&accepting("gzip;q=0, *"), GZIP));
+        assert!(accepts_encoding(&accepting("gzip"), IDENTITY));
+        assert!(!accepts_encoding(&accepting("identity;q=0"), IDENTITY));
+        assert!(!accepts_encoding(&accepting("*;q=0"), IDENTITY));
+    }
+
+    #[tokio::test]
+    async fn debug_routes_can_be_turned_off() {
+        let dir = TempDir::new();
+        dir.write("a.txt", "a");
+        let (addr, server) = spawn_test_server(Config {
+            no_debug_routes: true,
+            ..serving(&dir)
+        });
+        for target in ["/echo/x", "/user-agent"] {
+            let raw = format!(
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nUser-Agent: t\r\n\r\n",
+                target
+            );
+            assert_eq!(fetch(addr, raw).await.status, 404, "{}", target);
+        }
+        let response = fetch(addr, "GET /files/a.txt HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            fetch(addr, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await
+                .status,
+            200
+        );
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn two_listeners_share_a_port_with_reuse_port() {
+        let config = Config {
+            port: 0,
+            reuse_port: true,
+            ..Config::default()
+        };
+        let first = bind_listener(&config).unwrap();
+        let port = first.local_addr().unwrap().port();
+        let second = bind_listener(&Config { port, ..config }).unwrap();
+        assert_eq!(second.local_addr().unwrap().port(), port);
+        let without = Config {
+            port,
+            ..Config::default()
+        };
+        assert!(bind_listener(&without).is_err());
+    }
+
+    #[tokio::test]
+    async fn a_reload_applies_to_new_connections() {
+        let (old_dir, new_dir) = (TempDir::new(), TempDir::new());
+        old_dir.write("page.txt", "old");
+        new_dir.write("page.txt", "new");
+        let config = |dir: &TempDir| {
+            Arc::new(Config {
+                port: 0,
+                access_log: false,
+                ..serving(dir)
+            })
+        };
+        let shared = Arc::new(RwLock::new(config(&old_dir)));
+        let listener = bind_listener(&shared.read().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Arc::new(ServerState::default());
+        let current = {
+            let shared = shared.clone();
+            move || shared.read().unwrap().clone()
+        };
+        let running = tokio::spawn(run(Listener::Tcp(listener), None, current, server.clone()));
+        let get = "GET /files/page.txt HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let mut kept = TestClient::connect(addr).await;
+        kept.send(get).await;
+        assert_eq!(kept.response().await.text(), "old");
+        *shared.write().unwrap() = config(&new_dir);
+        assert_eq!(fetch(addr, get).await.text(), "new");
+        // A connection accepted before the reload keeps the configuration it had
+        kept.send(get).await;
+        assert_eq!(kept.response().await.text(), "old");
+        drop(kept);
+        ShutdownHandle { server, running }.stop().await;
+    }
+
+    #[test]
+    fn entity_tags_compare_per_rfc_7232() {
+        // The examples of RFC 7232 section 2.3.2
+        let cases = [
+            ("W/\"1\"", "W/\"1\"", false, true),
+            ("W/\"1\"", "W/\"2\"", false, false),
+            ("W/\"1\"", "\"1\"", false, true),
+            ("\"1\"", "\"1\"", true, true),
+        ];
+        for (a, b, strong, weak) in cases {
+            assert_eq!(strong_match(a, b), strong, "{} {}", a, b);
+            assert_eq!(strong_match(b, a), strong, "{} {}", b, a);
+            assert_eq!(weak_match(a, b), weak, "{} {}", a, b);
+            assert_eq!(weak_match(b, a), weak, "{} {}", b, a);
+        }
+        let modified = UNIX_EPOCH + Duration::from_secs(0x10);
+        assert_eq!(entity_tag(b"abc", modified, false), "W/\"3-10\"");
+        let strong = entity_tag(b"abc", modified, true);
+        assert!(
+            strong.starts_with('"') && strong.ends_with('"'),
+            "{}",
+            strong
+        );
+        assert_ne!(strong, entity_tag(b"abd", modified, true));
+        // A weak tag only changes with the size or time
+        assert_eq!(
+            entity_tag(b"abd", modified, false),
+            entity_tag(b"abc", modified, false)
+        );
+    }
+
+    #[tokio::test]
+    async fn file_errors_map_to_their_statuses() {
+        let status = |e: ServerError| {
+            let status = e.status();
+            assert_eq!(HttpResponse::from(e).status().0, status);
+            status
+        };
+        let dir = TempDir::new();
+        let missing = file_contents(&dir.0.join("missing.txt"), &Config::default()).await;
+        assert!(
+            matches!(missing, Err(ServerError::NotFound)),
+            "{:?}",
+            missing
+        );
+        assert_eq!(status(missing.unwrap_err()), 404);
+        let denied = ServerError::from(io::Error::from(io::ErrorKind::PermissionDenied));
+        assert!(matches!(denied, ServerError::Forbidden));
+        assert_eq!(status(denied), 403);
+        let invalid = ServerError::from(io::Error::from(io::ErrorKind::InvalidInput));
+        assert_eq!(status(invalid), 400);
+        let other = ServerError::from(io::Error::other("disk on fire"));
+        assert_eq!(other.to_string(), "disk on fire");
+        assert_eq!(status(other), 500);
+    }
+
+    #[tokio::test]
+    async fn redirects_become_absolute_with_a_host() {
+        let (addr, server) = spawn_test_server(Config {
+            redirects: vec![("/old".to_string(), "/new".to_string())],
+            absolute_redirects: true,
+            ..Config::default()
+        });
+        let response = fetch(addr, "GET /old HTTP/1.1\r\nHost: example.com:8080\r\n\r\n").await;
+        assert_eq!(response.status, 308);
+        assert_eq!(
+            response.header("location"),
+            Some("http://example.com:8080/new")
+        );
+        // Without a host there is nothing to make it absolute with
+        let response = fetch(addr, "GET /old HTTP/1.0\r\n\r\n").await;
+        assert_eq!(response.header("location"), Some("/new"));
+        server.stop().await;
+        let (addr, server) = spawn_test_server(Config {
+            redirects: vec![("/old".to_string(), "/new".to_string())],
+            ..Config::default()
+        });
+        let response = fetch(addr, "GET /old HTTP/1.1\r\nHost: example.com\r\n\r\n").await;
+        assert_eq!(response.header("location"), Some("/new"));
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn a_small_backlog_still_accepts_connections() {
+        let (addr, server) = spawn_test_server(Config {
+            backlog: 2,
+            ..Config::default()
+        });
+        let responses = (0..8).map(|i| {
+            fetch(
+                addr,
+                format!("GET /echo/{} HTTP/1.1\r\nHost: localhost\r\n\r\n", i),
+            )
+        });
+        for (i, response) in futures::future::join_all(responses)
+            .await
+            .into_iter()
+            .enumerate()
+        {
+            assert_eq!(response.status, 200);
+            assert_eq!(response.text(), i.to_string());
+        }
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn head_gets_the_status_and_headers_of_get() {
+        let dir = TempDir::new();
+        dir.write("digits.txt", "0123456789");
+        let (addr, server) = spawn_test_server(serving(&dir));
+        let request = |method: &str, extra: &str| {
+            format!(
+                "{} /files/digits.txt HTTP/1.1\r\nHost: localhost\r\n{}\r\n",
+                method, extra
+            )
+        };
+        let get = fetch(addr, request("GET", "Range: bytes=2-5\r\n")).await;
+        let mut client = TestClient::connect(addr).await;
+        client.send(request("HEAD", "Range: bytes=2-5\r\n")).await;
+        let head = client.head().await;
+        assert_eq!(head.status, 206);
+        assert_eq!(head.header("content-range"), Some("bytes 2-5/10"));
+        assert_eq!(head.header("content-range"), get.header("content-range"));
+        assert_eq!(head.header("content-length"), Some("4"));
+        // Nothing follows the head, the next response comes straight after it
+        client.send(request("HEAD", "Connection: close\r\n")).await;
+        let next = client.head().await;
+        assert_eq!(next.status, 200);
+        assert!(client.closed().await);
+        assert!(client.buf.is_empty());
+        let etag = get.header("etag").unwrap().to_string();
+        let raw = request("HEAD", &format!("If-None-Match: {}\r\n", etag));
+        let response = fetch(addr, raw).await;
+        assert_eq!(response.status, 304);
+        assert_eq!(response.header("etag"), Some(etag.as_str()));
+        server.stop().await;
+    }
+
+    #[test]
+    fn check_reports_a_valid_config_and_the_first_problem() {
+        let dir = TempDir::new();
+        let summary = check_config(&serving(&dir)).unwrap();
+        assert!(
+            summary.starts_with("Configuration OK: HTTP on 127.0.0.1:4221"),
+            "{}",
+            summary
+        );
+        assert!(summary.contains(&dir.path()), "{}", summary);
+        let missing = dir.0.join("missing").to_string_lossy().into_owned();
+        let e = check_config(&Config {
+            directory: Some(missing.clone()),
+            ..Config::default()
+        })
+        .unwrap_err();
+        assert!(
+            e.starts_with(&format!("cannot read directory {}: ", missing)),
+            "{}",
+            e
+        );
+        let e = check_config(&Config {
+            tls_cert: Some("cert.pem".to_string()),
+            ..Config::default()
+        })
+        .unwrap_err();
+        assert_eq!(e, "--tls-cert and --tls-key must be given together");
+    }
+
+    #[tokio::test]
+    async fn the_root_serves_the_directory_index() {
+        let dir = TempDir::new();
+        dir.write("notes.txt", "notes");
+        let get = "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let (addr, server) = spawn_test_server(Config {
+            list_directories: true,
+            ..serving(&dir)
+        });
+        // Without an index.html the listing stands in
+        let response = fetch(addr, get).await;
+        assert_eq!(response.status, 200);
+        assert!(response.text().contains("notes.txt"), "{}", response.text());
+        dir.write("index.html", "<h1>Home</h1>");
+        let response = fetch(addr, get).await;
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.header("content-type"),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(response.text(), "<h1>Home</h1>");
+        server.stop().await;
+        // With no directory at all the root stays an empty 200
+        let (addr, server) = spawn_test_server(Config::default());
+        let response = fetch(addr, get).await;
+        assert_eq!(response.status, 200);
+        assert!(response.body.is_empty());
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn bare_lf_line_endings_get_400() {
+        let (addr, server) = spawn_test_server(Config::default());
+        for raw in [
+            "GET /echo/a HTTP/1.1\nHost: localhost\n\n",
+            "GET /echo/a HTTP/1.1\r\nHost: localhost\nX-Smuggled: 1\r\n\r\n",
+        ] {
+            let mut client = TestClient::connect(addr).await;
+            client.send(raw).await;
+            // Answered at once rather than once some timeout runs out
+            assert_eq!(client.response().await.status, 400, "{:?}", raw);
+            assert!(client.closed().await);
+        }
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn cache_control_follows_the_file_pattern() {
+        let dir = TempDir::new();
+        dir.write("logo.png", b"\x89PNG");
+        dir.write("page.html", "<p>page</p>");
+        let (addr, server) = spawn_test_server(Config {
+            cache_control: vec![
+                ("*.png".to_string(), "public, max-age=31536000".to_string()),
+                ("*.css".to_string(), "no-cache".to_string()),
+            ],
+            ..serving(&dir)
+        });
+        let response = fetch(
+            addr,
+            "GET /files/logo.png HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.header("cache-control"),
+            Some("public, max-age=31536000")
+        );
+        let response = fetch(
+            addr,
+            "GET /files/page.html HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("cache-control"), None);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn the_checksum_trailer_matches_the_content() {
+        let dir = TempDir::new();
+        dir.write("check.txt", "123456789");
+        let (addr, server) = spawn_test_server(Config {
+            checksum_trailer: true,
+            ..serving(&dir)
+        });
+        let get = "GET /files/check.txt HTTP/1.1\r\nHost: localhost\r\n";
+        let response = fetch(addr, format!("{}TE: trailers\r\n\r\n", get)).await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("transfer-encoding"), Some("chunked"));
+        assert_eq!(response.header("trailer"), Some(CHECKSUM_TRAILER));
+        assert_eq!(response.text(), "123456789");
+        // The standard CRC-32 check value
+        assert_eq!(
+            response.trailers,
+            [(CHECKSUM_TRAILER.to_string(), "crc32=cbf43926".to_string())]
+        );
+        // A client that can't read trailers gets none
+        let response = fetch(addr, format!("{}\r\n", get)).await;
+        assert_eq!(response.header("trailer"), None);
+        assert!(response.trailers.is_empty());
+        server.stop().await;
+    }
+
+    #[test]
+    fn only_connections_idle_past_their_timeout_are_reaped() {
+        let now = Instant::now();
+        let tracked = |idle_for: Option<u64>, timeout: u64| TrackedConnection {
+            idle_since: idle_for.map(|secs| now - Duration::from_secs(secs)),
+            timeout: Duration::from_secs(timeout),
+            reaped: Arc::new(Notify::new()),
+        };
+        let connections = HashMap::from([
+            (1, tracked(Some(10), 5)),
+            (2, tracked(Some(1), 5)),
+            // Busy with a request, however long it takes
+            (3, tracked(None, 5)),
+            (4, tracked(Some(10), 30)),
+        ]);
+        assert_eq!(reap_expired(&connections, now), 1);
+        let notified = |id| connections[&id].reaped.notified().now_or_never().is_some();
+        assert!(notified(1));
+        assert!(!notified(2) && !notified(3) && !notified(4));
+    }
+
+    #[tokio::test]
+    async fn a_connection_that_never_sends_is_reaped() {
+        let args = vec![KEEPALIVE_TIMEOUT_ARG.to_string(), "1".to_string()];
+        let (addr, server) = spawn_test_server(Config::parse(args).unwrap());
+        let mut client = TestClient::connect(addr).await;
+        let idle = Instant::now();
+        assert!(client.closed().await);
+        // Closed by the reaper, which looks once every REAP_INTERVAL
+        let elapsed = idle.elapsed();
+        assert!(elapsed >= Duration::from_millis(900), "{:?}", elapsed);
+        assert!(
+            elapsed < Duration::from_secs(1) + REAP_INTERVAL * 2,
+            "{:?}",
+            elapsed
+        );
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn the_latency_histogram_counts_each_request() {
+        let (addr, server) = spawn_test_server(Config::default());
+        // Other tests add to the same histogram, so only lower bounds hold across reads
+        let before = metric(addr, "request_duration_seconds_count").await;
+        for i in 0..5 {
+            let raw = format!("GET /echo/{} HTTP/1.1\r\nHost: localhost\r\n\r\n", i);
+            fetch(addr, raw).await;
+        }
+        let response = fetch(addr, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        let text = response.text();
+        let value = |line: &str| line.rsplit(' ').next().unwrap().parse::<f64>().unwrap();
+        let buckets: Vec<f64> = text
+            .lines()
+            .filter(|line| line.starts_with("request_duration_seconds_bucket"))
+            .map(value)
+            .collect();
+        assert_eq!(buckets.len(), LATENCY_BOUNDS.len() + 1, "{}", text);
+        assert!(
+            buckets.windows(2).all(|pair| pair[0] <= pair[1]),
+            "{}",
+            text
+        );
+        let count = text
+            .lines()
+            .find(|line| line.starts_with("request_duration_seconds_count "))
+            .map(value)
+            .unwrap();
+        // The +Inf bucket holds every request
+        assert_eq!(buckets.last(), Some(&count));
+        assert!(count as u64 >= before + 5, "{} {}", count, before);
+        assert!(metric(addr, "bytes_served").await > 0);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn refusing_identity_without_a_usable_coding_gets_406() {
+        let dir = TempDir::new();
+        dir.write("a.txt", "a");
+        let (addr, server) = spawn_test_server(serving(&dir));
+        let get = |target: &str, accept: &str| {
+            format!(
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: {}\r\n\r\n",
+                target, accept
+            )
+        };
+        // zstd stands in for any coding the server cannot produce
+        for target in ["/echo/abc", "/files/a.txt"] {
+            let response = fetch(addr, get(target, "zstd, identity;q=0")).await;
+            assert_eq!(response.status, 406, "{}", target);
+            let response = fetch(addr, get(target, "zstd")).await;
+            assert_eq!(response.status, 200, "{}", target);
+            assert_eq!(response.header("content-encoding"), None, "{}", target);
+        }
+        let response = fetch(addr, get("/echo/abc", "br, identity;q=0")).await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("content-encoding"), Some("br"));
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn an_upload_cut_off_mid_stream_leaves_no_file() {
+        let dir = TempDir::new();
+        let (addr, server) = spawn_test_server(serving(&dir));
+        let data = vec![b'x'; DEFAULT_READ_BUFFER_BYTES * 4];
+        let head = |len: usize| {
+            format!(
+                "POST /files/big.bin HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n",
+                len
+            )
+        };
+        let mut client = TestClient::connect(addr).await;
+        client.send(head(data.len() * 2)).await;
+        client.send(&data).await;
+        client.stream.shutdown().await.unwrap();
+        assert_eq!(client.response().await.status, 400);
+        let left: Vec<_> = std::fs::read_dir(&dir.0).unwrap().collect();
+        assert!(left.is_empty(), "{:?}", left);
+        // Written across many reads, the whole body lands under the final name only
+        let mut raw = head(data.len()).into_bytes();
+        raw.extend_from_slice(&data);
+        assert_eq!(fetch(addr, raw).await.status, 201);
+        assert_eq!(std::fs::read(dir.0.join("big.bin")).unwrap(), data);
+        assert_eq!(std::fs::read_dir(&dir.0).unwrap().count(), 1);
+        server.stop().await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn the_root_answers_over_a_unix_socket() {
+        let dir = TempDir::new();
+        let path = format!("{}/server.sock", dir.path());
+        // A socket left behind by an earlier run is replaced
+        drop(bind_unix(&path).unwrap());
+        let listener = bind_unix(&path).unwrap();
+        let config = Arc::new(Config {
+            access_log: false,
+            ..Config::default()
+        });
+        let server = Arc::new(ServerState::default());
+        let running = tokio::spawn(run(
+            Listener::Unix(listener),
+            None,
+            move || config.clone(),
+            server.clone(),
+        ));
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        time::timeout(TEST_TIMEOUT, stream.read_to_string(&mut response))
+            .await
+            .expect("the server did not answer in time")
+            .unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        ShutdownHandle { server, running }.stop().await;
+        // Anything else at the path is left alone
+        dir.write("regular", "keep me");
+        assert!(bind_unix(&format!("{}/regular", dir.path())).is_err());
+        assert_eq!(
+            std::fs::read_to_string(dir.0.join("regular")).unwrap(),
+            "keep me"
+        );
+    }
+
+    #[tokio::test]
+    async fn only_text_types_carry_a_charset() {
+        let dir = TempDir::new();
+        dir.write("data.bin", [0u8, 1, 2]);
+        dir.write("photo.jpg", [0xff, 0xd8]);
+        let (addr, server) = spawn_test_server(serving(&dir));
+        let content_type = |target: &str| {
+            let raw = format!(
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nUser-Agent: t\r\n\r\n",
+                target
+            );
+            async move {
+                fetch(addr, raw)
+                    .await
+                    .header("content-type")
+                    .map(str::to_string)
+            }
+        };
+        let text = Some("text/plain; charset=utf-8".to_string());
+        assert_eq!(content_type("/echo/abc").await, text);
+        assert_eq!(content_type("/user-agent").await, text);
+        assert_eq!(
+            content_type("/files/data.bin").await.as_deref(),
+            Some("application/octet-stream")
+        );
+        assert_eq!(
+            content_type("/files/photo.jpg").await.as_deref(),
+            Some("image/jpeg")
+        );
+        server.stop().await;
+    }
+
+    #[test]
+    fn control_characters_in_the_request_line_are_rejected() {
+        let parses = |line: &str| {
+            HttpRequest::from_str(
+                &format!("{}\r\nHost: localhost\r\n", line),
+                &Config::default(),
+            )
+            .is_ok()
+        };
+        assert!(parses("GET /files/a.txt HTTP/1.1"));
+        assert!(!parses("GET /files/a\u{0}.txt HTTP/1.1"));
+        assert!(!parses("GET /files/a\u{1b}.txt HTTP/1.1"));
+        assert!(!parses("GET /files/a\u{7f}.txt HTTP/1.1"));
+        assert!(!parses("G\u{1}ET /files/a.txt HTTP/1.1"));
+    }
+
+    #[tokio::test]
+    async fn a_control_byte_in_the_path_touches_no_file() {
+        let dir = TempDir::new();
+        let (addr, server) = spawn_test_server(serving(&dir));
+        let response = fetch(
+            addr,
+            "POST /files/evil\x01.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\nevil",
+        )
+        .await;
+        assert_eq!(response.status, 400);
+        let left: Vec<_> = std::fs::read_dir(&dir.0).unwrap().collect();
+        assert!(left.is_empty(), "{:?}", left);
+        let response = fetch(
+            addr,
+            "GET /files/a\x00b HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.status, 400);
+        server.stop().await;
     }
 }
@@ -0,0 +1,65 @@
+// `--check` runs the real binary, since it ends the process with its exit status
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn check(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_http-server-starter-rust"))
+        .arg("--check")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+// A scratch directory, removed again on drop
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn a_valid_config_exits_0() {
+    let dir = TempDir::new("check-valid");
+    let output = check(&[
+        "--directory",
+        dir.0.to_str().unwrap(),
+        "--log-level",
+        "error",
+    ]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    assert!(output.stderr.is_empty(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.starts_with("Configuration OK: HTTP on "),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn a_missing_directory_exits_1_with_the_reason() {
+    let dir = TempDir::new("check-missing");
+    let missing = dir.0.join("missing");
+    let output = check(&["--directory", missing.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let expected = format!("error: cannot read directory {}: ", missing.display());
+    assert!(stderr.starts_with(&expected), "{}", stderr);
+}
+
+#[test]
+fn an_invalid_flag_exits_2() {
+    let output = check(&["--port", "http"]);
+    assert_eq!(output.status.code(), Some(2), "{:?}", output);
+    assert!(!output.stderr.is_empty());
+}